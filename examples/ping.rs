@@ -3,7 +3,7 @@ extern crate pretty_env_logger;
 #[macro_use]
 extern crate log;
 
-use fastping_rs::PingResult::{Idle, Receive};
+use fastping_rs::PingResult::{Idle, LowTtl, Pruned, Receive};
 use fastping_rs::Pinger;
 
 fn main() {
@@ -22,12 +22,18 @@ fn main() {
     loop {
         match results.recv() {
             Ok(result) => match result {
-                Idle { addr } => {
+                Idle { addr, .. } => {
                     error!("Idle Address {}.", addr);
                 }
-                Receive { addr, rtt, seq } => {
+                Receive { addr, rtt, seq, .. } => {
                     info!("Receive {} from Address {} in {:?}.", seq, addr, rtt);
                 }
+                Pruned { addr, .. } => {
+                    info!("Pruned Address {}.", addr);
+                }
+                LowTtl { addr, ttl, .. } => {
+                    warn!("Low TTL {} from Address {}.", ttl, addr);
+                }
             },
             Err(_) => panic!("Worker threads disconnected before the solution was found!"),
         }
@@ -1,72 +1,276 @@
+// a panic on the send loop or a listener silently stops all monitoring.  Outside of
+// tests, invariants are spelled out with expect and anything else is handled
+#![deny(clippy::unwrap_used)]
+
+#[cfg(feature = "ipnet")]
+extern crate ipnet;
 extern crate pnet;
 extern crate pnet_macros_support;
 #[macro_use]
 extern crate log;
 extern crate rand;
 
+mod lock;
 mod ping;
+mod pinger_core;
+mod prefix;
+mod quality;
+#[cfg(feature = "trace")]
+mod trace;
+mod transport;
+mod watch;
 
-use ping::{send_pings, Ping, ReceivedPing};
-use pnet::packet::icmp::echo_reply::EchoReplyPacket;
+#[cfg(feature = "ipnet")]
+pub use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+use lock::MutexExt;
+use ping::{
+    new_identifier, parse_echo_reply, payload_token, send_pings, unmapped, Context, Identifiers,
+    Name, RoundFeeds,
+};
+pub use ping::{ReceivedPing, MIN_PACKET_SIZE, TOKEN_PACKET_SIZE};
+pub use pinger_core::{IdentifierPolicy, MatchMode, PingerCore};
+use pnet::packet::icmpv6;
 use pnet::packet::ip::IpNextHeaderProtocols;
 use pnet::packet::ipv4::Ipv4Packet;
-use pnet::packet::ipv6::Ipv6Packet;
 use pnet::packet::Packet;
-use pnet::packet::{icmp, icmpv6};
 use pnet::transport::transport_channel;
 use pnet::transport::TransportChannelType::Layer4;
 use pnet::transport::TransportProtocol::{Ipv4, Ipv6};
+use pnet::transport::TransportReceiver;
 use pnet::transport::{icmp_packet_iter, icmpv6_packet_iter};
-use pnet::transport::{TransportReceiver, TransportSender};
-use std::collections::BTreeMap;
+pub use prefix::PrefixCap;
+pub use quality::{QualityWeights, TargetStats, MAX_OUTCOMES};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+#[cfg(feature = "trace")]
+use std::io::Write;
 use std::net::IpAddr;
 use std::sync::mpsc::{channel, Receiver, Sender};
-use std::sync::{Arc, Mutex, RwLock};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
 use std::thread;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+#[cfg(feature = "trace")]
+pub use trace::{replay, TraceError};
+#[cfg(feature = "trace")]
+use trace::{Event, Recorder, SharedRecorder};
+use transport::{SharedTransport, Transport};
+pub use watch::Debouncer;
 
 // result type returned by fastping_rs::Pinger::new()
 pub type NewPingerResult = Result<(Pinger, Receiver<PingResult>), String>;
 
+// most addresses Pinger::add_ipnet will expand a network into, a /16 for ipv4 or a /112 for ipv6
+#[cfg(feature = "ipnet")]
+pub const MAX_NET_TARGETS: u32 = 1 << 16;
+
 // ping result type.  Idle represents pings that have not received a repsonse within the max_rtt.
-// Receive represents pings which have received a repsonse
+// Receive represents pings which have received a repsonse.  Pruned follows the Idle of a
+// target which was removed for being idle longer than the prune threshold.  LowTtl follows
+// the Receive of a reply whose ttl is below the threshold set with Pinger::set_low_ttl.
+//
+// emitted_at is when the result was handed to the results channel.  Results come out of the
+// channel in emitted_at order, so it never goes backwards, for a target or across targets, no
+// matter how replies are delayed, reordered or duplicated on the way.  Measurements such as rtt
+// describe the probe itself and carry no such guarantee.
+//
+// timestamp is the wall clock time the result was emitted, for lining results up with other
+// logs.  It's the system clock as it is, so unlike emitted_at it goes back when the clock is
+// stepped back.  It's only taken with Pinger::set_wall_clock enabled and None otherwise
+#[derive(Debug, Clone, PartialEq)]
 pub enum PingResult {
     Idle {
         addr: IpAddr,
+        emitted_at: Instant,
+        timestamp: Option<SystemTime>,
     },
     Receive {
         addr: IpAddr,
         rtt: Duration,
         seq: u16,
         ttl: u8,
+        identifier: u16,
+        payload_len: usize,
+        emitted_at: Instant,
+        timestamp: Option<SystemTime>,
+    },
+    Pruned {
+        addr: IpAddr,
+        emitted_at: Instant,
+        timestamp: Option<SystemTime>,
+    },
+    LowTtl {
+        addr: IpAddr,
+        ttl: u8,
+        emitted_at: Instant,
+        timestamp: Option<SystemTime>,
     },
 }
 
+impl PingResult {
+    // the target the result is for
+    pub fn addr(&self) -> IpAddr {
+        match *self {
+            PingResult::Idle { addr, .. }
+            | PingResult::Receive { addr, .. }
+            | PingResult::Pruned { addr, .. }
+            | PingResult::LowTtl { addr, .. } => addr,
+        }
+    }
+
+    // when the result was handed to the results channel
+    pub fn emitted_at(&self) -> Instant {
+        match *self {
+            PingResult::Idle { emitted_at, .. }
+            | PingResult::Receive { emitted_at, .. }
+            | PingResult::Pruned { emitted_at, .. }
+            | PingResult::LowTtl { emitted_at, .. } => emitted_at,
+        }
+    }
+
+    // the wall clock time the result was emitted, if wall clock timestamps are enabled
+    pub fn timestamp(&self) -> Option<SystemTime> {
+        match *self {
+            PingResult::Idle { timestamp, .. }
+            | PingResult::Receive { timestamp, .. }
+            | PingResult::Pruned { timestamp, .. }
+            | PingResult::LowTtl { timestamp, .. } => timestamp,
+        }
+    }
+
+    // the timestamp the way `ping -D` prints it, seconds since the unix epoch with
+    // microseconds in brackets, like "[1700000000.123456]"
+    pub fn formatted_timestamp(&self) -> Option<String> {
+        let since_epoch = self
+            .timestamp()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Some(format!(
+            "[{}.{:06}]",
+            since_epoch.as_secs(),
+            since_epoch.subsec_micros()
+        ))
+    }
+
+    fn stamp(&mut self, at: Instant, wall_clock: Option<SystemTime>) {
+        match *self {
+            PingResult::Idle {
+                ref mut emitted_at,
+                ref mut timestamp,
+                ..
+            }
+            | PingResult::Receive {
+                ref mut emitted_at,
+                ref mut timestamp,
+                ..
+            }
+            | PingResult::Pruned {
+                ref mut emitted_at,
+                ref mut timestamp,
+                ..
+            }
+            | PingResult::LowTtl {
+                ref mut emitted_at,
+                ref mut timestamp,
+                ..
+            } => {
+                *emitted_at = at;
+                *timestamp = wall_clock;
+            }
+        }
+    }
+}
+
+// summary of a completed round, yielded by Pinger::round_summaries.  results
+// holds everything the round emitted on the results channel, in order, sent the
+// number of probes which went out, made up of a probe to each of targets and the
+// retries, and delayed how many of them the prefix cap held back
+#[derive(Debug, Clone, PartialEq)]
+pub struct RoundSnapshot {
+    pub started_at: Instant,
+    pub ended_at: Instant,
+    pub sent: usize,
+    pub targets: usize,
+    pub retries: usize,
+    pub delayed: usize,
+    pub results: Vec<PingResult>,
+}
+
+impl RoundSnapshot {
+    // number of targets which replied
+    pub fn received(&self) -> usize {
+        self.results
+            .iter()
+            .filter(|result| matches!(result, PingResult::Receive { .. }))
+            .count()
+    }
+
+    // number of targets which didn't reply within the max_rtt
+    pub fn idle(&self) -> usize {
+        self.results
+            .iter()
+            .filter(|result| matches!(result, PingResult::Idle { .. }))
+            .count()
+    }
+
+    // fraction of the round's probed targets which didn't reply to any of their
+    // probes, 0 for a round without any
+    pub fn loss(&self) -> f64 {
+        if self.targets == 0 {
+            0.0
+        } else {
+            self.idle() as f64 / self.targets as f64
+        }
+    }
+}
+
 pub struct Pinger {
-    // Number of milliseconds of an idle timeout. Once it passed,
-    // the library calls an idle callback function.  Default is 2000
-    max_rtt: Arc<Duration>,
+    // addresses to ping on each run, with their outstanding probes, and the
+    // max_rtt after which a target without a reply is idle.  Default is 2000ms
+    core: Arc<Mutex<PingerCore>>,
+
+    // signalled when a target is added or the pinger stopped, for a send loop
+    // waiting on an empty core
+    targets_changed: Arc<Condvar>,
+
+    // identifiers of the ipv4 targets, so the listener can drop unrelated replies early
+    identifiers: Identifiers,
 
-    // map of addresses to ping on each run
-    targets: Arc<Mutex<BTreeMap<IpAddr, Ping>>>,
+    // how replies are matched to probes, the listener only drops replies by
+    // identifier when matching on them
+    match_mode: Arc<Mutex<MatchMode>>,
+
+    // source of all randomness, seeded from entropy unless a seed is set
+    rng: Arc<Mutex<StdRng>>,
+
+    // what to do when a new target draws an identifier already in use
+    identifier_policy: Arc<Mutex<IdentifierPolicy>>,
 
     // Size in bytes of the payload to send.  Default is 16 bytes
-    size: usize,
+    size: Arc<Mutex<usize>>,
+
+    // ttl set on both transports, None while they're left at the system default
+    ttl: Arc<Mutex<Option<u8>>>,
+
+    // byte the payload of probes is filled with, around the token if there is one
+    fill_byte: Arc<Mutex<u8>>,
 
     // sender end of the channel for piping results to client
     results_sender: Sender<PingResult>,
 
-    // sender end of libpnet icmp v4 transport channel
-    tx: Arc<Mutex<TransportSender>>,
+    // emission time of the latest result, held while a result is stamped and sent
+    last_emitted: Arc<Mutex<Instant>>,
 
-    // receiver end of libpnet icmp v4 transport channel
-    rx: Arc<Mutex<TransportReceiver>>,
+    // ttl below which replies are followed by a PingResult::LowTtl, None for never
+    low_ttl: Arc<Mutex<Option<u8>>>,
 
-    // sender end of libpnet icmp v6 transport channel
-    txv6: Arc<Mutex<TransportSender>>,
+    // whether results are stamped with the wall clock time
+    wall_clock: Arc<Mutex<bool>>,
 
-    // receiver end of libpnet icmp v6 transport channel
-    rxv6: Arc<Mutex<TransportReceiver>>,
+    // sender end of libpnet icmp v4 transport channel
+    tx: SharedTransport,
+
+    // sender end of libpnet icmp v6 transport channel
+    txv6: SharedTransport,
 
     // sender for internal result passing beween threads
     thread_tx: Sender<ReceivedPing>,
@@ -79,13 +283,47 @@ pub struct Pinger {
 
     // flag to stop pinging
     stop: Arc<Mutex<bool>>,
+
+    // flag to send an unmeasured round of probes before the first real round
+    prewarm: Arc<Mutex<bool>>,
+
+    // optional limit on how fast targets sharing a network prefix are probed
+    prefix_cap: Arc<Mutex<Option<PrefixCap>>>,
+
+    // number of probes held back by the prefix cap
+    delayed_sends: Arc<Mutex<u64>>,
+
+    // feeds of completed rounds handed out by round_summaries
+    round_feeds: RoundFeeds,
+
+    // how loss, rtt and jitter count towards quality_score
+    quality_weights: Arc<Mutex<QualityWeights>>,
+
+    // recorder for probes, replies and results when tracing is enabled
+    #[cfg(feature = "trace")]
+    trace: SharedRecorder,
+
+    // name the pinger's log messages are labelled with, empty when unnamed
+    name: Name,
 }
 
 impl Pinger {
     // initialize the pinger and start the icmp and icmpv6 listeners
     pub fn new(_max_rtt: Option<u64>, _size: Option<usize>) -> NewPingerResult {
-        let targets = BTreeMap::new();
-        let (sender, receiver) = channel();
+        Pinger::new_named("", _max_rtt, _size)
+    }
+
+    // initialize a pinger whose log messages are labelled with the given name, to
+    // tell apart several pingers running in one process
+    pub fn new_named(name: &str, _max_rtt: Option<u64>, _size: Option<usize>) -> NewPingerResult {
+        if let Some(size) = _size {
+            if size < MIN_PACKET_SIZE {
+                return Err(format!(
+                    "Packet size {} is smaller than an echo request header ({} bytes)",
+                    size, MIN_PACKET_SIZE
+                ));
+            }
+        }
 
         let protocol = Layer4(Ipv4(IpNextHeaderProtocols::Icmp));
         let (tx, rx) = match transport_channel(4096, protocol) {
@@ -99,61 +337,165 @@ impl Pinger {
             Err(e) => return Err(e.to_string()),
         };
 
+        let (mut pinger, receiver) =
+            Pinger::with_transports(_max_rtt, _size, Box::new(tx), Box::new(txv6));
+        pinger.set_name(name);
+        pinger.start_listener(rx, rxv6);
+        Ok((pinger, receiver))
+    }
+
+    // ping the targets continuously and call on_change with a target and whether it's
+    // up each time it goes down or comes back, once threshold results in a row say so
+    // (see Debouncer).  Results only go to the callback, the returned pinger is there
+    // to change the targets and settings and to stop pinging
+    pub fn watch_state<F>(addrs: &[IpAddr], threshold: u32, on_change: F) -> Result<Pinger, String>
+    where
+        F: Fn(IpAddr, bool) + Send + 'static,
+    {
+        let (pinger, results) = Pinger::new(None, None)?;
+        for &addr in addrs {
+            pinger.insert_target(addr, 0)?;
+        }
+        pinger.watch(results, threshold, on_change);
+        Ok(pinger)
+    }
+
+    fn watch<F>(&self, results: Receiver<PingResult>, threshold: u32, on_change: F)
+    where
+        F: Fn(IpAddr, bool) + Send + 'static,
+    {
+        thread::spawn(move || {
+            let mut debouncer = Debouncer::new(threshold);
+            for result in results.iter() {
+                if let Some((addr, up)) = debouncer.observe(&result) {
+                    on_change(addr, up);
+                }
+            }
+        });
+        self.run_pinger();
+    }
+
+    // initialize the pinger without starting any listeners, probes are sent out
+    // through the given transports
+    fn with_transports(
+        _max_rtt: Option<u64>,
+        _size: Option<usize>,
+        tx: Box<dyn Transport>,
+        txv6: Box<dyn Transport>,
+    ) -> (Pinger, Receiver<PingResult>) {
+        let max_rtt = Duration::from_millis(_max_rtt.unwrap_or(2000));
+        let core = PingerCore::new(max_rtt);
+        let (sender, receiver) = channel();
         let (thread_tx, thread_rx) = channel();
 
-        let mut pinger = Pinger {
-            max_rtt: Arc::new(Duration::from_millis(2000)),
-            targets: Arc::new(Mutex::new(targets)),
-            size: _size.unwrap_or(16),
+        let pinger = Pinger {
+            identifiers: core.identifiers(),
+            match_mode: Arc::new(Mutex::new(MatchMode::Header)),
+            core: Arc::new(Mutex::new(core)),
+            targets_changed: Arc::new(Condvar::new()),
+            rng: Arc::new(Mutex::new(StdRng::from_entropy())),
+            identifier_policy: Arc::new(Mutex::new(IdentifierPolicy::Reroll)),
+            size: Arc::new(Mutex::new(_size.unwrap_or(16))),
+            ttl: Arc::new(Mutex::new(None)),
+            fill_byte: Arc::new(Mutex::new(0)),
             results_sender: sender,
+            last_emitted: Arc::new(Mutex::new(Instant::now())),
+            wall_clock: Arc::new(Mutex::new(false)),
+            low_ttl: Arc::new(Mutex::new(None)),
             tx: Arc::new(Mutex::new(tx)),
-            rx: Arc::new(Mutex::new(rx)),
             txv6: Arc::new(Mutex::new(txv6)),
-            rxv6: Arc::new(Mutex::new(rxv6)),
             thread_rx: Arc::new(Mutex::new(thread_rx)),
             thread_tx,
             timer: Arc::new(RwLock::new(Instant::now())),
             stop: Arc::new(Mutex::new(false)),
+            prewarm: Arc::new(Mutex::new(false)),
+            prefix_cap: Arc::new(Mutex::new(None)),
+            delayed_sends: Arc::new(Mutex::new(0)),
+            round_feeds: Arc::new(Mutex::new(Vec::new())),
+            quality_weights: Arc::new(Mutex::new(QualityWeights::default())),
+            #[cfg(feature = "trace")]
+            trace: Arc::new(Mutex::new(None)),
+            name: Name::default(),
         };
-        if let Some(rtt_value) = _max_rtt {
-            pinger.max_rtt = Arc::new(Duration::from_millis(rtt_value));
-        }
-        if let Some(size_value) = _size {
-            pinger.size = size_value;
-        }
+        (pinger, receiver)
+    }
 
-        pinger.start_listener();
-        Ok((pinger, receiver))
+    fn set_name(&mut self, name: &str) {
+        self.name = Name::new(name);
+        self.core.locked().set_name(name);
     }
 
-    // add either an ipv4 or ipv6 target address for pinging
+    // the name given at construction, empty for an unnamed pinger.  Handy as a
+    // label when exporting the pinger's results or counters like delayed_sends
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    // add either an ipv4 or ipv6 target address for pinging.  ipv4-mapped ipv6
+    // addresses like ::ffff:192.0.2.1 are pinged, and reported, as the ipv4
+    // address they map
     pub fn add_ipaddr(&self, ipaddr: &str) {
-        let addr = ipaddr.parse::<IpAddr>();
-        match addr {
-            Ok(valid_addr) => {
-                debug!("Address added {}", valid_addr);
-                let new_ping = Ping::new(valid_addr);
-                self.targets.lock().unwrap().insert(valid_addr, new_ping);
-            }
-            Err(e) => {
-                error!("Error adding ip address {}. Error: {}", ipaddr, e);
-            }
-        };
+        self.add_ipaddr_with_sequence_number(ipaddr, 0)
     }
 
     // add either an ipv4 or ipv6 target address for pinging
     pub fn add_ipaddr_with_sequence_number(&self, ipaddr: &str, seq: u16) {
-        let addr = ipaddr.parse::<IpAddr>();
-        match addr {
-            Ok(valid_addr) => {
-                debug!("Address added {}", valid_addr);
-                let new_ping = Ping::new_with_seq(valid_addr, seq);
-                self.targets.lock().unwrap().insert(valid_addr, new_ping);
-            }
-            Err(e) => {
-                error!("Error adding ip address {}. Error: {}", ipaddr, e);
+        if let Err(e) = self.try_add_ipaddr_with_sequence_number(ipaddr, seq) {
+            error!("{}{}", self.name, e);
+        }
+    }
+
+    // add either an ipv4 or ipv6 target address for pinging, returning why it
+    // couldn't be added instead of logging it
+    pub fn try_add_ipaddr(&self, ipaddr: &str) -> Result<(), String> {
+        self.try_add_ipaddr_with_sequence_number(ipaddr, 0)
+    }
+
+    pub fn try_add_ipaddr_with_sequence_number(
+        &self,
+        ipaddr: &str,
+        seq: u16,
+    ) -> Result<(), String> {
+        let addr = ipaddr
+            .parse::<IpAddr>()
+            .map_err(|e| format!("Error adding ip address {}. Error: {}", ipaddr, e))?;
+        self.insert_target(addr, seq)?;
+        debug!("{}Address added {}", self.name, addr);
+        Ok(())
+    }
+
+    // add every host address of a network as a target.  ipv4 networks wider than a /31
+    // leave out their network and broadcast addresses, ipv6 networks are added whole, and
+    // host bits set in the network are ignored.  Networks with more than MAX_NET_TARGETS
+    // addresses are refused.  Returns the number of targets added
+    #[cfg(feature = "ipnet")]
+    pub fn add_ipnet<N: Into<IpNet>>(&self, net: N) -> Result<usize, String> {
+        let net = net.into().trunc();
+        let host_bits = net.max_prefix_len() - net.prefix_len();
+        if host_bits > MAX_NET_TARGETS.trailing_zeros() as u8 {
+            return Err(format!(
+                "network {} has more than {} addresses",
+                net, MAX_NET_TARGETS
+            ));
+        }
+        debug!("{}Network added {}", self.name, net);
+        let mut added = 0;
+        for addr in net.hosts() {
+            match self.insert_target(addr, 0) {
+                Ok(()) => added += 1,
+                Err(e) => error!("{}{}", self.name, e),
             }
-        };
+        }
+        Ok(added)
+    }
+
+    // add every host address of a network in CIDR notation, like "192.0.2.0/24"
+    #[cfg(feature = "ipnet")]
+    pub fn add_cidr(&self, cidr: &str) -> Result<usize, String> {
+        match cidr.parse::<IpNet>() {
+            Ok(net) => self.add_ipnet(net),
+            Err(e) => Err(format!("Error adding network {}. Error: {}", cidr, e)),
+        }
     }
 
     // remove a previously added ipv4 or ipv6 target address
@@ -161,19 +503,307 @@ impl Pinger {
         let addr = ipaddr.parse::<IpAddr>();
         match addr {
             Ok(valid_addr) => {
-                debug!("Address removed {}", valid_addr);
-                self.targets.lock().unwrap().remove(&valid_addr);
+                let valid_addr = unmapped(valid_addr);
+                debug!("{}Address removed {}", self.name, valid_addr);
+                let mut core = self.core.locked();
+                #[cfg(feature = "trace")]
+                trace::record(
+                    &self.trace,
+                    Instant::now(),
+                    Event::Remove { addr: valid_addr },
+                );
+                core.remove_target(valid_addr);
             }
             Err(e) => {
-                error!("Error removing ip address {}. Error: {}", ipaddr, e);
+                error!(
+                    "{}Error removing ip address {}. Error: {}",
+                    self.name, ipaddr, e
+                );
+            }
+        };
+    }
+
+    fn insert_target(&self, addr: IpAddr, seq: u16) -> Result<(), String> {
+        let addr = unmapped(addr);
+        let mut core = self.core.locked();
+        let identifier = {
+            let mut rng = self.rng.locked();
+            let mut identifier = new_identifier(addr, &mut *rng);
+            match *self.identifier_policy.locked() {
+                IdentifierPolicy::Share => {}
+                IdentifierPolicy::Reroll => {
+                    // with every identifier taken sharing is all that's left
+                    while core.identifier_taken(addr, identifier) && core.identifiers_free() {
+                        identifier = new_identifier(addr, &mut *rng);
+                    }
+                }
+                IdentifierPolicy::Reject => {
+                    if core.identifier_taken(addr, identifier) {
+                        return Err(format!(
+                            "Error adding ip address {}. Error: identifier {} is already in use",
+                            addr, identifier
+                        ));
+                    }
+                }
             }
+            identifier
         };
+        #[cfg(feature = "trace")]
+        trace::record(
+            &self.trace,
+            Instant::now(),
+            Event::Add {
+                addr,
+                identifier,
+                seq,
+            },
+        );
+        core.add_target(addr, identifier, seq, Instant::now());
+        self.targets_changed.notify_all();
+        Ok(())
+    }
+
+    // start recording every target change, probe sent, reply received and result
+    // emitted to the writer.  The recorded trace can be checked with fastping_rs::replay
+    #[cfg(feature = "trace")]
+    pub fn record_trace<W: Write + Send + 'static>(&self, writer: W) {
+        let mode = *self.match_mode.locked();
+        // held until the recorder is in place, so no target change or probe is missed
+        let core = self.core.locked();
+        let mut recorder = Recorder::new(writer, self.name.clone());
+        let now = Instant::now();
+        // replays start out matching by header and without targets
+        if mode != MatchMode::Header {
+            recorder.record(now, &Event::Mode(mode));
+        }
+        for addr in core.targets() {
+            if let (Some(identifier), Some(seq)) =
+                (core.identifier(addr), core.sequence_number(addr))
+            {
+                recorder.record(
+                    now,
+                    &Event::Add {
+                        addr,
+                        identifier,
+                        seq,
+                    },
+                );
+            }
+        }
+        *self.trace.locked() = Some(recorder);
+    }
+
+    // stop recording and flush the trace
+    #[cfg(feature = "trace")]
+    pub fn stop_trace(&self) {
+        if let Some(mut recorder) = self.trace.locked().take() {
+            recorder.flush();
+        }
+    }
+
+    // derive all of the pinger's randomness, the identifiers given to targets added
+    // from here on and the payload tokens of the probes sent, from the seed so runs
+    // can be reproduced.  This is meant for testing and debugging, a seeded
+    // pinger's identifiers and tokens are predictable and give no protection
+    // against spoofed replies
+    pub fn set_seed(&self, seed: u64) {
+        *self.rng.locked() = StdRng::seed_from_u64(seed);
+    }
+
+    // follow the Receive of every reply with a ttl below the threshold with a
+    // PingResult::LowTtl, hinting at an unexpectedly long path or a routing loop.
+    // The ttl of ipv6 replies isn't known, so they never count as low.  None, the
+    // default, reports no low ttls
+    pub fn set_low_ttl(&self, threshold: Option<u8>) {
+        *self.low_ttl.locked() = threshold;
+    }
+
+    // when enabled, results carry the wall clock time they were emitted as their
+    // timestamp, see PingResult::formatted_timestamp.  Off by default, leaving
+    // timestamp None and the system clock unread
+    pub fn set_wall_clock(&self, enabled: bool) {
+        *self.wall_clock.locked() = enabled;
+    }
+
+    // when enabled, each run first sends one throwaway probe to every target and
+    // waits out the max_rtt without reporting any results, so the arp/neighbor
+    // caches are warm and the first measured rtts aren't inflated.  Off by default
+    pub fn set_prewarm(&self, prewarm: bool) {
+        *self.prewarm.locked() = prewarm;
+    }
+
+    // limit how fast targets sharing a network prefix are probed, spreading each
+    // prefix's probes across the round.  None, the default, sends every probe at
+    // the start of the round
+    pub fn set_prefix_cap(&self, cap: Option<PrefixCap>) {
+        *self.prefix_cap.locked() = cap;
+    }
+
+    // number of probes the prefix cap has held back since the pinger was created
+    pub fn delayed_sends(&self) -> u64 {
+        *self.delayed_sends.locked()
+    }
+
+    // a feed of every round completed from here on, summarized.  The iterator
+    // blocks until the next round ends and finishes once the pinger and its send
+    // loop are gone.  Rounds completed while nobody reads the feed queue up
+    pub fn round_summaries(&self) -> impl Iterator<Item = RoundSnapshot> {
+        let (sender, receiver) = channel();
+        self.round_feeds.locked().push(sender);
+        receiver.into_iter()
+    }
+
+    // whether the latest probe to the target made it onto the wire, telling a local
+    // send failure apart from a target which doesn't answer.  None for an unknown
+    // target or before its first probe
+    pub fn last_send_ok(&self, addr: IpAddr) -> Option<bool> {
+        self.core.locked().last_send_ok(unmapped(addr))
+    }
+
+    // the target's reply statistics since it was added, None for an unknown target
+    pub fn stats(&self, addr: IpAddr) -> Option<TargetStats> {
+        self.core.locked().stats(unmapped(addr))
+    }
+
+    // fraction of the target's probes sent within the last window which went
+    // unanswered, the loss alerting rules usually look at.  Covers only the probes
+    // sent since the target was added, and at most its last MAX_OUTCOMES.  None for
+    // an unknown target or if none of its probes in the window has had its round end
+    pub fn windowed_loss(&self, addr: IpAddr, window: Duration) -> Option<f64> {
+        self.core
+            .locked()
+            .windowed_loss(unmapped(addr), window, Instant::now())
+    }
+
+    // health of the target between 0 (worst) and 1 (best), combining its loss,
+    // rtt and jitter as weighed by set_quality_weights.  None for an unknown
+    // target, before any of its rounds ended or with a negative or non-finite
+    // weight, see QualityWeights for the defaults
+    pub fn quality_score(&self, addr: IpAddr) -> Option<f64> {
+        let weights = *self.quality_weights.locked();
+        self.stats(addr)?.quality_score(&weights)
+    }
+
+    pub fn set_quality_weights(&self, weights: QualityWeights) {
+        *self.quality_weights.locked() = weights;
+    }
+
+    // choose what happens when a new ipv4 target draws an identifier another
+    // target already uses, see IdentifierPolicy.  Reroll by default
+    pub fn set_identifier_policy(&self, policy: IdentifierPolicy) {
+        *self.identifier_policy.locked() = policy;
+    }
+
+    // remove targets which haven't replied for longer than the given duration,
+    // reporting a PingResult::Pruned for each.  A target which is added again
+    // starts over as if it were new.  None, the default, never prunes
+    pub fn set_prune_idle_after(&self, after: Option<Duration>) {
+        self.core.locked().set_prune_after(after);
+    }
+
+    // send each target which hasn't replied up to this many more probes within a
+    // round, with a new sequence number each, before reporting it idle.  The round's
+    // max_rtt is split evenly between the first probe and its retries, so a round
+    // takes no longer, and a reply to any of them counts.  ipv6 probes carry no
+    // sequence number, so ipv6 targets are only retried when matching by payload
+    // token.  0, the default, never retries
+    pub fn set_retries_per_round(&self, retries: u8) {
+        self.core.locked().set_retries_per_round(retries);
+    }
+
+    // the settings below can be changed from any thread, also while the pinger
+    // runs.  Each change is made whole under the setting's lock, and whatever uses
+    // a setting reads it once: every probe is built with one size and match mode
+    // and sent with one ttl, and a change shows from the next probe sent.  Settings
+    // checked against each other, packet size and match mode, are changed under
+    // both their locks so they're never seen apart
+
+    // how long to wait for replies after the round's latest probe before
+    // reporting targets idle.  Applies to the round in progress as well.  A max_rtt
+    // too long to add to the clock, such as Duration::MAX, waits for good
+    pub fn set_max_rtt(&self, max_rtt: Duration) {
+        self.core.locked().set_max_rtt(max_rtt);
+    }
+
+    // size in bytes of the probes sent from here on, at least MIN_PACKET_SIZE, or
+    // TOKEN_PACKET_SIZE when matching by payload token
+    pub fn set_payload_size(&self, size: usize) -> Result<(), String> {
+        let mut current = self.size.locked();
+        let needed = match *self.match_mode.locked() {
+            MatchMode::Header => MIN_PACKET_SIZE,
+            MatchMode::PayloadToken => TOKEN_PACKET_SIZE,
+        };
+        if size < needed {
+            return Err(format!(
+                "Packet size {} is smaller than the {} bytes needed",
+                size, needed
+            ));
+        }
+        *current = size;
+        Ok(())
+    }
+
+    // time to live, or hop limit for ipv6, of the probes sent from here on.  If the
+    // ipv6 transport refuses it the ipv4 one is put back to the ttl set before.
+    // Before the first ttl is set the transports are at the system default, which
+    // can't be read back, so a first ttl refused for ipv6 stays set for ipv4 and
+    // the error says so
+    pub fn set_ttl(&self, ttl: u8) -> Result<(), String> {
+        let mut current = self.ttl.locked();
+        let mut tx = self.tx.locked();
+        let mut txv6 = self.txv6.locked();
+        if let Err(e) = tx.set_ttl(ttl) {
+            return Err(format!("Error setting ttl {}. Error: {}", ttl, e));
+        }
+        if let Err(e) = txv6.set_ttl(ttl) {
+            let restored = match *current {
+                Some(previous) => tx.set_ttl(previous).is_ok(),
+                None => false,
+            };
+            if !restored {
+                return Err(format!(
+                    "Error setting ttl {} for ipv6, ipv4 probes keep it. Error: {}",
+                    ttl, e
+                ));
+            }
+            return Err(format!("Error setting ttl {}. Error: {}", ttl, e));
+        }
+        *current = Some(ttl);
+        Ok(())
+    }
+
+    // byte the payload of the probes sent from here on is filled with, 0 by default.
+    // A payload token still takes the first 8 bytes of the payload
+    pub fn set_fill_byte(&self, fill: u8) {
+        *self.fill_byte.locked() = fill;
+    }
+
+    // choose how replies are matched to probes, by their icmp header (the default)
+    // or by a random token written into each probe's payload.  Matching by token
+    // needs a packet size of at least TOKEN_PACKET_SIZE
+    pub fn set_match_mode(&self, mode: MatchMode) -> Result<(), String> {
+        let size = self.size.locked();
+        if mode == MatchMode::PayloadToken && *size < TOKEN_PACKET_SIZE {
+            return Err(format!(
+                "Packet size {} has no room for a payload token ({} bytes needed)",
+                *size, TOKEN_PACKET_SIZE
+            ));
+        }
+        let mut match_mode = self.match_mode.locked();
+        self.core.locked().set_match_mode(mode);
+        *match_mode = mode;
+        #[cfg(feature = "trace")]
+        trace::record(&self.trace, Instant::now(), Event::Mode(mode));
+        Ok(())
     }
 
     // stop running the continous pinger
     pub fn stop_pinger(&self) {
-        let mut stop = self.stop.lock().unwrap();
-        *stop = true;
+        *self.stop.locked() = true;
+        // taking the core lock makes sure a send loop checking for targets is
+        // either already waiting or will see the stop
+        let _core = self.core.locked();
+        self.targets_changed.notify_all();
     }
 
     // run one round of pinging and stop
@@ -188,20 +818,34 @@ impl Pinger {
 
     // run pinger either once or continuously
     fn run_pings(&self, run_once: bool) {
-        let thread_rx = self.thread_rx.clone();
-        let tx = self.tx.clone();
-        let txv6 = self.txv6.clone();
-        let results_sender = self.results_sender.clone();
-        let stop = self.stop.clone();
-        let targets = self.targets.clone();
-        let timer = self.timer.clone();
-        let max_rtt = self.max_rtt.clone();
-        let size = self.size;
+        let ctx = Context {
+            name: self.name.clone(),
+            size: self.size.clone(),
+            fill_byte: self.fill_byte.clone(),
+            timer: self.timer.clone(),
+            stop: self.stop.clone(),
+            results_sender: self.results_sender.clone(),
+            last_emitted: self.last_emitted.clone(),
+            wall_clock: self.wall_clock.clone(),
+            low_ttl: self.low_ttl.clone(),
+            thread_rx: self.thread_rx.clone(),
+            tx: self.tx.clone(),
+            txv6: self.txv6.clone(),
+            core: self.core.clone(),
+            targets_changed: self.targets_changed.clone(),
+            rng: self.rng.clone(),
+            prewarm: self.prewarm.clone(),
+            prefix_cap: self.prefix_cap.clone(),
+            delayed_sends: self.delayed_sends.clone(),
+            round_feeds: self.round_feeds.clone(),
+            #[cfg(feature = "trace")]
+            trace: self.trace.clone(),
+        };
 
         {
-            let mut stop = self.stop.lock().unwrap();
+            let mut stop = self.stop.locked();
             if run_once {
-                debug!("Running pinger for one round");
+                debug!("{}Running pinger for one round", self.name);
                 *stop = true;
             } else {
                 *stop = false;
@@ -209,85 +853,51 @@ impl Pinger {
         }
 
         if run_once {
-            send_pings(
-                size,
-                timer,
-                stop,
-                results_sender,
-                thread_rx,
-                tx,
-                txv6,
-                targets,
-                max_rtt,
-            );
+            send_pings(ctx);
         } else {
             thread::spawn(move || {
-                send_pings(
-                    size,
-                    timer,
-                    stop,
-                    results_sender,
-                    thread_rx,
-                    tx,
-                    txv6,
-                    targets,
-                    max_rtt,
-                );
+                send_pings(ctx);
             });
         }
     }
 
-    fn start_listener(&self) {
+    fn start_listener(&self, rx: TransportReceiver, rxv6: TransportReceiver) {
         // start icmp listeners in the background and use internal channels for results
 
         // setup ipv4 listener
         let thread_tx = self.thread_tx.clone();
-        let rx = self.rx.clone();
-        let timer = self.timer.clone();
-        let stop = self.stop.clone();
+        let identifiers = self.identifiers.clone();
+        let match_mode = self.match_mode.clone();
+        let name = self.name.clone();
 
         thread::spawn(move || {
-            let mut receiver = rx.lock().unwrap();
-            let mut iter = icmp_packet_iter(&mut receiver);
+            let mut receiver = rx;
             loop {
-                match iter.next() {
-                    Ok((packet, addr)) => match EchoReplyPacket::new(packet.packet()) {
-                        Some(echo_reply) => {
-                            if packet.get_icmp_type() == icmp::IcmpType::new(0) {
-                                let start_time = timer.read().unwrap();
-
-                                let ttl = Ipv4Packet::new(packet.packet())
-                                    .map(|p| p.get_ttl())
-                                    .unwrap_or_default();
-
-                                match thread_tx.send(ReceivedPing {
-                                    addr,
-                                    identifier: echo_reply.get_identifier(),
-                                    sequence_number: echo_reply.get_sequence_number(),
-                                    rtt: Instant::now().duration_since(*start_time),
-                                    ttl: ttl,
-                                }) {
-                                    Ok(_) => {}
-                                    Err(e) => {
-                                        if !*stop.lock().unwrap() {
-                                            error!("Error sending ping result on channel: {}", e)
-                                        } else {
-                                            return;
-                                        }
-                                    }
-                                }
-                            } else {
-                                debug!(
-                                    "ICMP type other than reply (0) received from {:?}: {:?}",
-                                    addr,
-                                    packet.get_icmp_type()
-                                );
-                            }
-                        }
-                        None => {}
-                    },
+                let parsed = match icmp_packet_iter(&mut receiver).next() {
+                    Ok((packet, addr)) => {
+                        let received_at = Instant::now();
+                        let filter = match *match_mode.locked() {
+                            MatchMode::Header => Some(&identifiers),
+                            MatchMode::PayloadToken => None,
+                        };
+                        parse_echo_reply(packet.packet(), addr, received_at, filter, &name)
+                    }
                     Err(e) => {
-                        error!("An error occurred while reading: {}", e);
+                        error!("{}An error occurred while reading: {}", name, e);
+                        None
+                    }
+                };
+                if let Some(mut reply) = parsed {
+                    // the iterator hands out the packet without its ip header, which
+                    // is still at the start of the receive buffer
+                    reply.ttl = Ipv4Packet::new(&receiver.buffer)
+                        .map(|ip| ip.get_ttl())
+                        .unwrap_or_default();
+                    // the receiving end lives as long as the pinger, once
+                    // it's gone there's nothing left to listen for
+                    if thread_tx.send(reply).is_err() {
+                        debug!("{}Pinger dropped, stopping ipv4 listener", name);
+                        return;
                     }
                 }
             }
@@ -295,47 +905,43 @@ impl Pinger {
 
         // setup ipv6 listener
         let thread_txv6 = self.thread_tx.clone();
-        let rxv6 = self.rxv6.clone();
-        let timerv6 = self.timer.clone();
-        let stopv6 = self.stop.clone();
+        let name = self.name.clone();
 
         thread::spawn(move || {
-            let mut receiver = rxv6.lock().unwrap();
+            let mut receiver = rxv6;
             let mut iter = icmpv6_packet_iter(&mut receiver);
             loop {
                 match iter.next() {
                     Ok((packet, addr)) => {
+                        let received_at = Instant::now();
                         if packet.get_icmpv6_type() == icmpv6::Icmpv6Type::new(129) {
-                            let start_time = timerv6.read().unwrap();
-                            let ttl = Ipv6Packet::new(packet.packet())
-                                .map(|p| p.get_hop_limit())
-                                .unwrap_or_default();
-                            match thread_txv6.send(ReceivedPing {
+                            let reply = ReceivedPing {
                                 addr,
                                 identifier: 0,
                                 sequence_number: 0,
-                                rtt: Instant::now().duration_since(*start_time),
-                                ttl: ttl,
-                            }) {
-                                Ok(_) => {}
-                                Err(e) => {
-                                    if !*stopv6.lock().unwrap() {
-                                        error!("Error sending ping result on channel: {}", e)
-                                    } else {
-                                        return;
-                                    }
-                                }
+                                received_at,
+                                // raw icmpv6 sockets don't hand out the ip header, so
+                                // the hop limit is unknown
+                                ttl: 0,
+                                // the echo body starts with the identifier and sequence number
+                                payload_len: packet.payload().len().saturating_sub(4),
+                                token: packet.payload().get(4..).and_then(payload_token),
+                            };
+                            if thread_txv6.send(reply).is_err() {
+                                debug!("{}Pinger dropped, stopping ipv6 listener", name);
+                                return;
                             }
                         } else {
                             debug!(
-                                "ICMP type other than reply (129) received from {:?}: {:?}",
+                                "{}ICMP type other than reply (129) received from {:?}: {:?}",
+                                name,
                                 addr,
                                 packet.get_icmpv6_type()
                             );
                         }
                     }
                     Err(e) => {
-                        error!("An error occurred while reading: {}", e);
+                        error!("{}An error occurred while reading: {}", name, e);
                     }
                 }
             }
@@ -346,36 +952,58 @@ impl Pinger {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use rand::Rng;
+    use std::collections::{HashMap, HashSet};
+    use std::io;
+    use std::panic;
+    use transport::mock::MockTransport;
+
+    // a pinger sending through the mock, which also stands in for the listeners
+    fn mock_pinger(max_rtt: u64, mock: &MockTransport) -> (Pinger, Receiver<PingResult>) {
+        let (pinger, results) = Pinger::with_transports(
+            Some(max_rtt),
+            None,
+            Box::new(mock.clone()),
+            Box::new(mock.clone()),
+        );
+        mock.connect(pinger.thread_tx.clone());
+        (pinger, results)
+    }
 
     #[test]
     fn test_newpinger() {
         // test we can create a new pinger with optional arguments,
         // test it returns the new pinger and a client channel
         // test we can use the client channel
-        match Pinger::new(Some(3000 as u64), Some(24)) {
+        match Pinger::new(Some(3000), Some(24)) {
             Ok((test_pinger, test_channel)) => {
-                assert_eq!(test_pinger.max_rtt, Arc::new(Duration::new(3, 0)));
-                assert_eq!(test_pinger.size, 24);
+                assert_eq!(
+                    test_pinger.core.lock().unwrap().max_rtt(),
+                    Duration::new(3, 0)
+                );
+                assert_eq!(*test_pinger.size.lock().unwrap(), 24);
 
+                let emitted_at = Instant::now();
                 match test_pinger.results_sender.send(PingResult::Idle {
                     addr: "127.0.0.1".parse::<IpAddr>().unwrap(),
+                    emitted_at,
+                    timestamp: None,
                 }) {
                     Ok(_) => match test_channel.recv() {
-                        Ok(result) => match result {
-                            PingResult::Idle { addr } => {
-                                assert_eq!(addr, "127.0.0.1".parse::<IpAddr>().unwrap());
+                        Ok(result) => assert_eq!(
+                            result,
+                            PingResult::Idle {
+                                addr: "127.0.0.1".parse::<IpAddr>().unwrap(),
+                                emitted_at,
+                                timestamp: None,
                             }
-                            _ => {}
-                        },
-                        Err(_) => assert!(false),
+                        ),
+                        Err(_) => panic!("client channel disconnected"),
                     },
-                    Err(_) => assert!(false),
+                    Err(_) => panic!("client channel disconnected"),
                 }
             }
-            Err(e) => {
-                println!("Test failed: {}", e);
-                assert!(false)
-            }
+            Err(e) => panic!("Test failed: {}", e),
         };
     }
 
@@ -384,43 +1012,1164 @@ mod tests {
         match Pinger::new(None, None) {
             Ok((test_pinger, _)) => {
                 test_pinger.add_ipaddr("127.0.0.1");
-                assert_eq!(test_pinger.targets.lock().unwrap().len(), 1);
+                assert_eq!(test_pinger.core.lock().unwrap().len(), 1);
                 assert!(test_pinger
-                    .targets
+                    .core
                     .lock()
                     .unwrap()
-                    .contains_key(&"127.0.0.1".parse::<IpAddr>().unwrap()));
+                    .contains("127.0.0.1".parse::<IpAddr>().unwrap()));
 
                 test_pinger.remove_ipaddr("127.0.0.1");
-                assert_eq!(test_pinger.targets.lock().unwrap().len(), 0);
-                assert_eq!(
-                    test_pinger
-                        .targets
-                        .lock()
-                        .unwrap()
-                        .contains_key(&"127.0.0.1".parse::<IpAddr>().unwrap()),
-                    false
-                );
-            }
-            Err(e) => {
-                println!("Test failed: {}", e);
-                assert!(false)
+                assert_eq!(test_pinger.core.lock().unwrap().len(), 0);
+                assert!(!test_pinger
+                    .core
+                    .lock()
+                    .unwrap()
+                    .contains("127.0.0.1".parse::<IpAddr>().unwrap()));
             }
+            Err(e) => panic!("Test failed: {}", e),
+        }
+    }
+
+    #[cfg(feature = "ipnet")]
+    fn cidr_targets(cidr: &str) -> Result<Vec<String>, String> {
+        let mock = MockTransport::silent();
+        let (test_pinger, _) = mock_pinger(50, &mock);
+        let added = test_pinger.add_cidr(cidr)?;
+        let targets: Vec<String> = test_pinger
+            .core
+            .lock()
+            .unwrap()
+            .targets()
+            .iter()
+            .map(|addr| addr.to_string())
+            .collect();
+        assert_eq!(added, targets.len());
+        Ok(targets)
+    }
+
+    #[test]
+    fn test_ipv4_mapped() {
+        let mock = MockTransport::echo();
+        let (test_pinger, test_channel) = mock_pinger(50, &mock);
+        let v4 = "192.0.2.1".parse::<IpAddr>().unwrap();
+        test_pinger.add_ipaddr("::ffff:192.0.2.1");
+        assert!(test_pinger.core.lock().unwrap().contains(v4));
+        assert!(test_pinger.core.lock().unwrap().identifier(v4).unwrap() != 0);
+        test_pinger.ping_once();
+
+        // the probe went out as an ipv4 echo request
+        let sent = mock.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].addr, v4);
+        assert_eq!(sent[0].packet[0], 8);
+        match test_channel.try_recv() {
+            Ok(PingResult::Receive { addr, .. }) => assert_eq!(addr, v4),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(test_pinger
+            .stats("::ffff:192.0.2.1".parse().unwrap())
+            .is_some());
+
+        test_pinger.remove_ipaddr("::ffff:192.0.2.1");
+        assert!(test_pinger.core.lock().unwrap().is_empty());
+    }
+
+    #[cfg(feature = "ipnet")]
+    #[test]
+    fn test_add_cidr() {
+        assert_eq!(
+            cidr_targets("192.0.2.0/30").unwrap(),
+            vec!["192.0.2.1", "192.0.2.2"]
+        );
+        // host bits are ignored
+        assert_eq!(
+            cidr_targets("192.0.2.6/30").unwrap(),
+            vec!["192.0.2.5", "192.0.2.6"]
+        );
+        // point to point links have no network or broadcast address
+        assert_eq!(
+            cidr_targets("192.0.2.7/31").unwrap(),
+            vec!["192.0.2.6", "192.0.2.7"]
+        );
+        assert_eq!(cidr_targets("192.0.2.7/32").unwrap(), vec!["192.0.2.7"]);
+        assert_eq!(
+            cidr_targets("2001:db8::7/127").unwrap(),
+            vec!["2001:db8::6", "2001:db8::7"]
+        );
+        assert_eq!(
+            cidr_targets("2001:db8::7/128").unwrap(),
+            vec!["2001:db8::7"]
+        );
+        assert_eq!(cidr_targets("2001:db8::/126").unwrap().len(), 4);
+        assert_eq!(cidr_targets("10.0.0.0/16").unwrap().len(), 65534);
+
+        assert!(cidr_targets("10.0.0.0/15").is_err());
+        assert!(cidr_targets("2001:db8::/64").is_err());
+        assert!(cidr_targets("192.0.2.0/33").is_err());
+        assert!(cidr_targets("192.0.2.0").is_err());
+
+        let mock = MockTransport::silent();
+        let (test_pinger, _) = mock_pinger(50, &mock);
+        let net: Ipv4Net = "192.0.2.0/29".parse().unwrap();
+        assert_eq!(test_pinger.add_ipnet(net), Ok(6));
+        let net: Ipv6Net = "2001:db8::/125".parse().unwrap();
+        assert_eq!(test_pinger.add_ipnet(net), Ok(8));
+        assert_eq!(test_pinger.core.lock().unwrap().len(), 14);
+    }
+
+    #[test]
+    fn test_tracked_identifiers() {
+        let (test_pinger, _) = Pinger::new(None, None).unwrap();
+        test_pinger.add_ipaddr("127.0.0.1");
+        test_pinger.add_ipaddr("::1");
+        let identifier = test_pinger
+            .core
+            .lock()
+            .unwrap()
+            .identifier("127.0.0.1".parse::<IpAddr>().unwrap())
+            .unwrap();
+        assert_eq!(
+            test_pinger
+                .identifiers
+                .read()
+                .unwrap()
+                .keys()
+                .collect::<Vec<_>>(),
+            vec![&identifier]
+        );
+
+        // re-adding a target replaces its identifier instead of leaking the old one
+        test_pinger.add_ipaddr("127.0.0.1");
+        assert_eq!(test_pinger.identifiers.read().unwrap().len(), 1);
+
+        test_pinger.remove_ipaddr("127.0.0.1");
+        test_pinger.remove_ipaddr("::1");
+        assert!(test_pinger.identifiers.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_identifier_policy() {
+        let identifier = |pinger: &Pinger, addr: &str| {
+            pinger
+                .core
+                .lock()
+                .unwrap()
+                .identifier(addr.parse::<IpAddr>().unwrap())
+        };
+        // reseeding before each add makes the second target draw the first's identifier
+        let colliding = |policy: IdentifierPolicy| {
+            let mock = MockTransport::silent();
+            let (test_pinger, _) = mock_pinger(50, &mock);
+            test_pinger.set_identifier_policy(policy);
+            test_pinger.set_seed(7);
+            test_pinger.try_add_ipaddr("192.0.2.1").unwrap();
+            test_pinger.set_seed(7);
+            let added = test_pinger.try_add_ipaddr("192.0.2.2");
+            (test_pinger, added)
+        };
+
+        let (test_pinger, added) = colliding(IdentifierPolicy::Share);
+        assert!(added.is_ok());
+        assert_eq!(
+            identifier(&test_pinger, "192.0.2.1"),
+            identifier(&test_pinger, "192.0.2.2")
+        );
+        assert_eq!(test_pinger.identifiers.read().unwrap().len(), 1);
+
+        let (test_pinger, added) = colliding(IdentifierPolicy::Reroll);
+        assert!(added.is_ok());
+        assert_ne!(
+            identifier(&test_pinger, "192.0.2.1"),
+            identifier(&test_pinger, "192.0.2.2")
+        );
+        assert_eq!(test_pinger.identifiers.read().unwrap().len(), 2);
+        // a target re-added over itself doesn't collide with its own identifier
+        let before = identifier(&test_pinger, "192.0.2.1");
+        test_pinger.set_seed(7);
+        test_pinger.try_add_ipaddr("192.0.2.1").unwrap();
+        assert_eq!(identifier(&test_pinger, "192.0.2.1"), before);
+
+        // an ipv4 target drawing identifier 0, which every ipv6 target has, doesn't
+        // keep ipv6 targets out under any policy
+        for &policy in &[
+            IdentifierPolicy::Share,
+            IdentifierPolicy::Reroll,
+            IdentifierPolicy::Reject,
+        ] {
+            let mock = MockTransport::silent();
+            let (test_pinger, _) = mock_pinger(50, &mock);
+            test_pinger.set_identifier_policy(policy);
+            test_pinger.set_seed(3421);
+            test_pinger.try_add_ipaddr("192.0.2.1").unwrap();
+            assert_eq!(identifier(&test_pinger, "192.0.2.1"), Some(0));
+            test_pinger.try_add_ipaddr("2001:db8::1").unwrap();
+            assert_eq!(identifier(&test_pinger, "2001:db8::1"), Some(0));
         }
+
+        let (test_pinger, added) = colliding(IdentifierPolicy::Reject);
+        assert!(added.unwrap_err().contains("already in use"));
+        assert!(!test_pinger
+            .core
+            .lock()
+            .unwrap()
+            .contains("192.0.2.2".parse::<IpAddr>().unwrap()));
+        // ipv6 targets have no identifier to collide on
+        test_pinger.try_add_ipaddr("2001:db8::1").unwrap();
+        test_pinger.try_add_ipaddr("2001:db8::2").unwrap();
+        assert!(test_pinger.try_add_ipaddr("nonsense").is_err());
     }
 
     #[test]
     fn test_stop() {
         match Pinger::new(None, None) {
             Ok((test_pinger, _)) => {
-                assert_eq!(*test_pinger.stop.lock().unwrap(), false);
+                assert!(!*test_pinger.stop.lock().unwrap());
                 test_pinger.stop_pinger();
-                assert_eq!(*test_pinger.stop.lock().unwrap(), true);
+                assert!(*test_pinger.stop.lock().unwrap());
             }
-            Err(e) => {
-                println!("Test failed: {}", e);
-                assert!(false)
+            Err(e) => panic!("Test failed: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_round_summaries() {
+        let (test_pinger, test_channel) = Pinger::new(Some(100), None).unwrap();
+        test_pinger.add_ipaddr("127.0.0.1");
+        test_pinger.add_ipaddr("::1");
+        let summaries = test_pinger.round_summaries();
+        test_pinger.run_pinger();
+        let rounds: Vec<RoundSnapshot> = summaries.take(3).collect();
+        test_pinger.stop_pinger();
+
+        let mut results = test_channel.iter();
+        let mut previous_end = None;
+        for round in rounds {
+            assert_eq!(round.sent, 2);
+            assert_eq!(round.targets, 2);
+            assert_eq!(round.received(), 2);
+            assert_eq!(round.idle(), 0);
+            assert_eq!(round.loss(), 0.0);
+            assert!(round.started_at <= round.ended_at);
+            if let Some(previous_end) = previous_end {
+                assert!(round.started_at >= previous_end);
+            }
+            previous_end = Some(round.ended_at);
+            // each round carries the results as they came out of the channel
+            for result in &round.results {
+                assert_eq!(results.next().as_ref(), Some(result));
+            }
+        }
+    }
+
+    #[test]
+    fn test_wait_for_targets() {
+        let mock = MockTransport::echo();
+        let (test_pinger, test_channel) = mock_pinger(500, &mock);
+        let summaries = test_pinger.round_summaries();
+        let started = *test_pinger.timer.read().unwrap();
+        test_pinger.run_pinger();
+
+        // without targets the send loop waits instead of running empty rounds,
+        // which would restart the timer each time
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(*test_pinger.timer.read().unwrap(), started);
+
+        // and starts a round as soon as a target is added
+        let added = Instant::now();
+        test_pinger.add_ipaddr("192.0.2.1");
+        match test_channel.recv_timeout(Duration::from_secs(1)) {
+            Ok(PingResult::Receive { .. }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(added.elapsed() < Duration::from_millis(250));
+
+        // once the targets are gone again, stopping ends the waiting send loop
+        test_pinger.remove_ipaddr("192.0.2.1");
+        thread::sleep(Duration::from_millis(600));
+        test_pinger.stop_pinger();
+        drop(test_pinger);
+        let (done, finished) = channel();
+        thread::spawn(move || {
+            let _ = summaries.count();
+            let _ = done.send(());
+        });
+        assert!(finished.recv_timeout(Duration::from_secs(1)).is_ok());
+
+        // a single round without targets returns straight away
+        let (test_pinger, _) = mock_pinger(500, &mock);
+        let once = Instant::now();
+        test_pinger.ping_once();
+        assert!(once.elapsed() < Duration::from_millis(250));
+    }
+
+    #[test]
+    fn test_last_send_ok() {
+        // probes to 192.0.2.1 can't be sent, 192.0.2.2 just doesn't answer
+        let mock = MockTransport::new(Box::new(|sent| {
+            if sent.addr == "192.0.2.1".parse::<IpAddr>().unwrap() {
+                Err(io::Error::other("network unreachable"))
+            } else {
+                Ok(Vec::new())
+            }
+        }));
+        let (test_pinger, _test_channel) = mock_pinger(20, &mock);
+        test_pinger.add_ipaddr("192.0.2.1");
+        test_pinger.add_ipaddr("192.0.2.2");
+        let unreachable = "192.0.2.1".parse::<IpAddr>().unwrap();
+        let silent = "192.0.2.2".parse::<IpAddr>().unwrap();
+        assert_eq!(test_pinger.last_send_ok(unreachable), None);
+
+        test_pinger.ping_once();
+        assert_eq!(test_pinger.last_send_ok(unreachable), Some(false));
+        assert_eq!(test_pinger.last_send_ok(silent), Some(true));
+        assert_eq!(
+            test_pinger.last_send_ok("192.0.2.9".parse::<IpAddr>().unwrap()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_retries_per_round() {
+        // 192.0.2.1 drops its first probe, 192.0.2.2 never replies
+        let first = "192.0.2.1".parse::<IpAddr>().unwrap();
+        let mut dropped = false;
+        let mock = MockTransport::new(Box::new(move |sent| {
+            if sent.addr != first {
+                Ok(Vec::new())
+            } else if !dropped {
+                dropped = true;
+                Ok(Vec::new())
+            } else {
+                Ok(vec![sent.reply()])
+            }
+        }));
+        let (test_pinger, test_channel) = mock_pinger(100, &mock);
+        test_pinger.add_ipaddr("192.0.2.1");
+        test_pinger.add_ipaddr("192.0.2.2");
+        test_pinger.set_retries_per_round(1);
+        test_pinger.ping_once();
+
+        let results: Vec<_> = test_channel.try_iter().collect();
+        assert_eq!(results.len(), 2);
+        match results[0] {
+            PingResult::Receive { addr, seq, rtt, .. } => {
+                assert_eq!(addr, first);
+                // answered by the retry
+                assert_eq!(seq, 2);
+                assert!(rtt < Duration::from_millis(50));
+            }
+            _ => panic!("expected a receive, got {:?}", results[0]),
+        }
+        match results[1] {
+            PingResult::Idle { addr, .. } => assert_eq!(addr.to_string(), "192.0.2.2"),
+            _ => panic!("expected an idle, got {:?}", results[1]),
+        }
+        let sent = mock.sent();
+        assert_eq!(sent.len(), 4);
+        assert_eq!(sent.iter().filter(|probe| probe.addr == first).count(), 2);
+    }
+
+    #[test]
+    fn test_retries_loss() {
+        let mock = MockTransport::silent();
+        let (test_pinger, _test_channel) = mock_pinger(20, &mock);
+        test_pinger.add_ipaddr("192.0.2.1");
+        test_pinger.add_ipaddr("192.0.2.2");
+        test_pinger.set_retries_per_round(1);
+        let mut summaries = test_pinger.round_summaries();
+        test_pinger.ping_once();
+
+        // loss is per target, however many probes each got
+        let round = summaries.next().unwrap();
+        assert_eq!(round.sent, 4);
+        assert_eq!(round.targets, 2);
+        assert_eq!(round.retries, 2);
+        assert_eq!(round.idle(), 2);
+        assert_eq!(round.loss(), 1.0);
+    }
+
+    #[test]
+    fn test_retries_ipv6() {
+        // replies to the first probe of each round take 25ms, arriving after a retry
+        // could have gone out, and retries go unanswered
+        let mut probes = 0;
+        let mock = MockTransport::new(Box::new(move |sent| {
+            probes += 1;
+            if probes > 2 {
+                return Ok(Vec::new());
+            }
+            let mut reply = sent.reply();
+            reply.received_at = sent.at + Duration::from_millis(25);
+            Ok(vec![reply])
+        }));
+        let (test_pinger, test_channel) = mock_pinger(40, &mock);
+        test_pinger.add_ipaddr("2001:db8::1");
+        test_pinger.set_retries_per_round(1);
+
+        // with the same header on every ipv6 probe there's no retry
+        test_pinger.ping_once();
+        assert_eq!(mock.sent().len(), 1);
+        test_channel.try_iter().for_each(drop);
+
+        // tokens tell the probes apart, and the rtt is the first probe's
+        test_pinger.set_match_mode(MatchMode::PayloadToken).unwrap();
+        test_pinger.ping_once();
+        assert_eq!(mock.sent().len(), 3);
+        let results: Vec<_> = test_channel.try_iter().collect();
+        assert_eq!(results.len(), 1);
+        match results[0] {
+            PingResult::Receive { rtt, .. } => assert!(rtt >= Duration::from_millis(25)),
+            _ => panic!("expected a receive, got {:?}", results[0]),
+        }
+    }
+
+    #[test]
+    fn test_fill_byte() {
+        let mock = MockTransport::silent();
+        let (test_pinger, _test_channel) = mock_pinger(20, &mock);
+        test_pinger.add_ipaddr("192.0.2.1");
+        test_pinger.add_ipaddr("2001:db8::1");
+        test_pinger.ping_once();
+        test_pinger.set_fill_byte(0x42);
+        test_pinger.set_payload_size(24).unwrap();
+        test_pinger.ping_once();
+        test_pinger.set_match_mode(MatchMode::PayloadToken).unwrap();
+        test_pinger.ping_once();
+
+        let sent = mock.sent();
+        assert_eq!(sent.len(), 6);
+        // zero filled by default
+        for probe in &sent[..2] {
+            assert!(probe.packet[8..].iter().all(|&byte| byte == 0));
+        }
+        for probe in &sent[2..4] {
+            assert_eq!(probe.packet.len(), 24);
+            assert!(probe.packet[8..].iter().all(|&byte| byte == 0x42));
+        }
+        // the token comes first, the fill after it
+        for probe in &sent[4..] {
+            assert_ne!(probe.packet[8..16], [0x42; 8]);
+            assert!(probe.packet[16..].iter().all(|&byte| byte == 0x42));
+        }
+    }
+
+    #[test]
+    fn test_set_ttl_refused() {
+        let (v4, v6) = (MockTransport::echo(), MockTransport::echo());
+        let (test_pinger, _test_channel) =
+            Pinger::with_transports(None, None, Box::new(v4.clone()), Box::new(v6.clone()));
+        test_pinger.set_ttl(32).unwrap();
+        assert_eq!((v4.ttl(), v6.ttl()), (Some(32), Some(32)));
+
+        // refused for ipv6, the ipv4 ttl is put back
+        v6.fail_ttl(true);
+        assert!(test_pinger.set_ttl(10).is_err());
+        assert_eq!((v4.ttl(), v6.ttl()), (Some(32), Some(32)));
+
+        // refused for ipv4, nothing changes
+        v6.fail_ttl(false);
+        v4.fail_ttl(true);
+        assert!(test_pinger.set_ttl(10).is_err());
+        assert_eq!((v4.ttl(), v6.ttl()), (Some(32), Some(32)));
+
+        // without a ttl to go back to, the error says ipv4 keeps the new one
+        let (v4, v6) = (MockTransport::echo(), MockTransport::echo());
+        let (test_pinger, _test_channel) =
+            Pinger::with_transports(None, None, Box::new(v4.clone()), Box::new(v6.clone()));
+        v6.fail_ttl(true);
+        let error = test_pinger.set_ttl(10).unwrap_err();
+        assert!(error.contains("ipv4 probes keep it"));
+        assert_eq!((v4.ttl(), v6.ttl()), (Some(10), None));
+    }
+
+    #[test]
+    fn test_runtime_settings() {
+        let mock = MockTransport::echo();
+        let (test_pinger, test_channel) = mock_pinger(20, &mock);
+        let test_pinger = Arc::new(test_pinger);
+        for n in 1..=8 {
+            test_pinger.add_ipaddr(&format!("192.0.2.{}", n));
+            test_pinger.add_ipaddr(&format!("2001:db8::{}", n));
+        }
+        test_pinger.run_pinger();
+
+        // hammer every setter from its own thread while rounds run
+        let setters: Vec<_> = (0..4)
+            .map(|task| {
+                let pinger = test_pinger.clone();
+                thread::spawn(move || {
+                    for i in 0..200u64 {
+                        match task {
+                            0 => pinger.set_max_rtt(Duration::from_millis(10 + i % 20)),
+                            1 => pinger
+                                .set_payload_size(if i % 2 == 0 { 16 } else { 64 })
+                                .unwrap(),
+                            2 => pinger.set_ttl(if i % 2 == 0 { 32 } else { 64 }).unwrap(),
+                            _ => {
+                                let mode = if i % 2 == 0 {
+                                    MatchMode::PayloadToken
+                                } else {
+                                    MatchMode::Header
+                                };
+                                pinger.set_match_mode(mode).unwrap();
+                            }
+                        }
+                        thread::sleep(Duration::from_micros(500));
+                    }
+                })
+            })
+            .collect();
+        for setter in setters {
+            setter.join().unwrap();
+        }
+        test_pinger.stop_pinger();
+        test_pinger.set_max_rtt(Duration::from_millis(20));
+
+        // every probe was built and sent with one whole setting of each
+        let sent = mock.sent();
+        assert!(!sent.is_empty());
+        for probe in &sent {
+            assert!(probe.packet.len() == 16 || probe.packet.len() == 64);
+            assert!(probe.ttl.is_none() || probe.ttl == Some(32) || probe.ttl == Some(64));
+        }
+        assert!(test_channel.try_iter().count() > 0);
+
+        // settings which don't fit together are refused
+        test_pinger.set_match_mode(MatchMode::PayloadToken).unwrap();
+        assert!(test_pinger.set_payload_size(MIN_PACKET_SIZE).is_err());
+        test_pinger.set_match_mode(MatchMode::Header).unwrap();
+        test_pinger.set_payload_size(MIN_PACKET_SIZE).unwrap();
+        assert!(test_pinger.set_match_mode(MatchMode::PayloadToken).is_err());
+        assert!(test_pinger.set_payload_size(MIN_PACKET_SIZE - 1).is_err());
+        assert_eq!(
+            test_pinger.core.lock().unwrap().max_rtt(),
+            Duration::from_millis(20)
+        );
+    }
+
+    #[test]
+    fn test_watch() {
+        // the target stops replying to its 4th to 7th probes
+        let mut probes = 0;
+        let mock = MockTransport::new(Box::new(move |sent| {
+            probes += 1;
+            if (4..=7).contains(&probes) {
+                Ok(Vec::new())
+            } else {
+                Ok(vec![sent.reply()])
+            }
+        }));
+        let (test_pinger, results) = mock_pinger(20, &mock);
+        test_pinger.add_ipaddr("192.0.2.1");
+        let (changes, changed) = channel();
+        test_pinger.watch(results, 3, move |addr, up| {
+            let _ = changes.send((addr, up));
+        });
+
+        let addr = "192.0.2.1".parse::<IpAddr>().unwrap();
+        let timeout = Duration::from_secs(2);
+        assert_eq!(changed.recv_timeout(timeout), Ok((addr, false)));
+        assert_eq!(changed.recv_timeout(timeout), Ok((addr, true)));
+        test_pinger.stop_pinger();
+        // the outage was reported once, and nothing else
+        thread::sleep(Duration::from_millis(100));
+        assert!(changed.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_prewarm() {
+        let (test_pinger, test_channel) = Pinger::new(Some(500), None).unwrap();
+        test_pinger.set_prewarm(true);
+        test_pinger.add_ipaddr("127.0.0.1");
+        test_pinger.ping_once();
+
+        // the throwaway probe went out as sequence number 1 but only the
+        // measured probe produced a result
+        let results: Vec<PingResult> = test_channel.try_iter().collect();
+        assert_eq!(results.len(), 1);
+        match results[0] {
+            PingResult::Receive { addr, seq, .. } => {
+                assert_eq!(addr, "127.0.0.1".parse::<IpAddr>().unwrap());
+                assert_eq!(seq, 2);
+            }
+            ref other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_prewarm_stats() {
+        let mock = MockTransport::echo();
+        let (test_pinger, _test_channel) = mock_pinger(20, &mock);
+        test_pinger.set_prewarm(true);
+        test_pinger.add_ipaddr("192.0.2.1");
+        test_pinger.ping_once();
+
+        // both probes were answered, but only the measured one counts
+        assert_eq!(mock.sent().len(), 2);
+        let stats = test_pinger
+            .stats("192.0.2.1".parse::<IpAddr>().unwrap())
+            .unwrap();
+        assert_eq!(stats.replies, 1);
+        assert_eq!(stats.lost, 0);
+    }
+
+    #[test]
+    fn test_prewarm_windowed_loss() {
+        // only the throwaway probe is answered
+        let mut probes = 0;
+        let mock = MockTransport::new(Box::new(move |sent| {
+            probes += 1;
+            if probes == 1 {
+                Ok(vec![sent.reply()])
+            } else {
+                Ok(Vec::new())
+            }
+        }));
+        let (test_pinger, _test_channel) = mock_pinger(20, &mock);
+        test_pinger.set_prewarm(true);
+        test_pinger.add_ipaddr("192.0.2.1");
+        test_pinger.ping_once();
+
+        let addr = "192.0.2.1".parse::<IpAddr>().unwrap();
+        assert_eq!(
+            test_pinger.windowed_loss(addr, Duration::from_secs(60)),
+            Some(1.0)
+        );
+    }
+
+    #[test]
+    fn test_mock_transport() {
+        let mock = MockTransport::echo();
+        let (test_pinger, test_channel) = mock_pinger(50, &mock);
+        test_pinger.add_ipaddr("192.0.2.1");
+        test_pinger.add_ipaddr("2001:db8::1");
+        test_pinger.ping_once();
+
+        assert_eq!(mock.sent().len(), 2);
+        let results: Vec<PingResult> = test_channel.try_iter().collect();
+        assert_eq!(results.len(), 2);
+        for result in results {
+            match result {
+                PingResult::Receive { addr, .. } => assert!(
+                    addr == "192.0.2.1".parse::<IpAddr>().unwrap()
+                        || addr == "2001:db8::1".parse::<IpAddr>().unwrap()
+                ),
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_packet_too_small() {
+        assert!(Pinger::new(None, Some(MIN_PACKET_SIZE - 1)).is_err());
+
+        // a probe that can't be built fails its send instead of the send loop
+        let mock = MockTransport::echo();
+        let (test_pinger, test_channel) = Pinger::with_transports(
+            Some(50),
+            Some(4),
+            Box::new(mock.clone()),
+            Box::new(mock.clone()),
+        );
+        mock.connect(test_pinger.thread_tx.clone());
+        test_pinger.add_ipaddr("192.0.2.1");
+        test_pinger.add_ipaddr("2001:db8::1");
+        test_pinger.ping_once();
+
+        assert!(mock.sent().is_empty());
+        let results: Vec<PingResult> = test_channel.try_iter().collect();
+        assert_eq!(results.len(), 2);
+        for result in results {
+            match result {
+                PingResult::Idle { .. } => {}
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+    }
+
+    // log messages captured by the test logger, from every test running alongside
+    static LOGGED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    struct CaptureLogger;
+
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            LOGGED.lock().unwrap().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn test_name() {
+        static CAPTURE: CaptureLogger = CaptureLogger;
+        if log::set_logger(&CAPTURE).is_ok() {
+            log::set_max_level(log::LevelFilter::Trace);
+        }
+
+        let mock = MockTransport::new(Box::new(|_| Err(io::Error::other("network unreachable"))));
+        let (mut test_pinger, _test_channel) = mock_pinger(50, &mock);
+        test_pinger.set_name("uplink-a");
+        assert_eq!(test_pinger.name(), "uplink-a");
+        test_pinger.add_ipaddr("nonsense");
+        test_pinger.add_ipaddr("192.0.2.1");
+        test_pinger.ping_once();
+
+        let logged: Vec<String> = LOGGED
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|line| line.starts_with("[uplink-a] "))
+            .cloned()
+            .collect();
+        assert!(logged
+            .iter()
+            .any(|line| line.contains("Error adding ip address nonsense")));
+        assert!(logged
+            .iter()
+            .any(|line| line.contains("Failed to send ping to 192.0.2.1")));
+
+        // unnamed pingers log without a label
+        assert_eq!(Name::default().to_string(), "");
+        assert_eq!(
+            Pinger::with_transports(None, None, Box::new(mock.clone()), Box::new(mock))
+                .0
+                .name(),
+            ""
+        );
+    }
+
+    #[test]
+    fn test_transport_panic() {
+        // the first probe panics inside the transport, poisoning the transport
+        // and target locks on the way out of the send loop
+        let mut panicked = false;
+        let mock = MockTransport::new(Box::new(move |sent| {
+            if !panicked {
+                panicked = true;
+                panic!("transport failure");
+            }
+            Ok(vec![sent.reply()])
+        }));
+        let (test_pinger, test_channel) = mock_pinger(50, &mock);
+        test_pinger.add_ipaddr("192.0.2.1");
+        let round = panic::catch_unwind(panic::AssertUnwindSafe(|| test_pinger.ping_once()));
+        assert!(round.is_err());
+        assert!(test_pinger.core.is_poisoned());
+
+        // later rounds and setters carry on
+        test_pinger.set_prewarm(false);
+        test_pinger.add_ipaddr("192.0.2.2");
+        test_pinger.ping_once();
+        let results: Vec<PingResult> = test_channel.try_iter().collect();
+        assert_eq!(results.len(), 2);
+        for result in results {
+            match result {
+                PingResult::Receive { .. } => {}
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_match_payload_token() {
+        // a middlebox rewriting the identifier and sequence number of every reply
+        let rewriting = || {
+            MockTransport::new(Box::new(|sent| {
+                let mut reply = sent.reply();
+                reply.identifier = reply.identifier.wrapping_add(1);
+                reply.sequence_number = 1000;
+                Ok(vec![reply])
+            }))
+        };
+
+        let mock = rewriting();
+        let (test_pinger, test_channel) = mock_pinger(50, &mock);
+        test_pinger.add_ipaddr("192.0.2.1");
+        test_pinger.ping_once();
+        match test_channel.try_recv() {
+            Ok(PingResult::Idle { .. }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        let mock = rewriting();
+        let (test_pinger, test_channel) = mock_pinger(50, &mock);
+        test_pinger.set_match_mode(MatchMode::PayloadToken).unwrap();
+        test_pinger.add_ipaddr("192.0.2.1");
+        test_pinger.add_ipaddr("2001:db8::1");
+        test_pinger.ping_once();
+        test_pinger.ping_once();
+        let results: Vec<PingResult> = test_channel.try_iter().collect();
+        assert_eq!(results.len(), 4);
+        for result in results {
+            match result {
+                PingResult::Receive { .. } => {}
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+        // every probe carries its own token
+        let tokens: HashSet<Option<u64>> = mock
+            .sent()
+            .iter()
+            .map(|sent| payload_token(&sent.packet[8..]))
+            .collect();
+        assert_eq!(tokens.len(), 4);
+
+        let (test_pinger, _) = Pinger::with_transports(
+            None,
+            Some(12),
+            Box::new(MockTransport::silent()),
+            Box::new(MockTransport::silent()),
+        );
+        assert!(test_pinger.set_match_mode(MatchMode::PayloadToken).is_err());
+        assert!(test_pinger.set_match_mode(MatchMode::Header).is_ok());
+    }
+
+    #[test]
+    fn test_results_dropped() {
+        let mock = MockTransport::echo();
+        let (test_pinger, test_channel) = mock_pinger(50, &mock);
+        test_pinger.add_ipaddr("192.0.2.1");
+        drop(test_channel);
+        // results with nowhere to go are dropped, the round still completes
+        test_pinger.ping_once();
+        assert_eq!(mock.sent().len(), 1);
+    }
+
+    #[test]
+    fn test_receive_identifier_and_payload_len() {
+        let (test_pinger, test_channel) = Pinger::new(Some(500), Some(24)).unwrap();
+        test_pinger.add_ipaddr("127.0.0.1");
+        test_pinger.add_ipaddr("::1");
+        let identifier = test_pinger
+            .core
+            .lock()
+            .unwrap()
+            .identifier("127.0.0.1".parse::<IpAddr>().unwrap())
+            .unwrap();
+        test_pinger.ping_once();
+
+        let results: Vec<PingResult> = test_channel.try_iter().collect();
+        assert_eq!(results.len(), 2);
+        for result in results {
+            match result {
+                PingResult::Receive {
+                    addr,
+                    identifier: reply_identifier,
+                    payload_len,
+                    ttl,
+                    ..
+                } => {
+                    // 24 byte packets leave 16 bytes after the echo header
+                    assert_eq!(payload_len, 16);
+                    // the ttl comes out of the ipv4 header, ipv6 has none to offer
+                    if addr.is_ipv4() {
+                        assert_eq!(reply_identifier, identifier);
+                        assert!(ttl > 0);
+                    } else {
+                        assert_eq!(reply_identifier, 0);
+                        assert_eq!(ttl, 0);
+                    }
+                }
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_low_ttl() {
+        let mock = MockTransport::new(Box::new(|sent| {
+            let mut reply = sent.reply();
+            reply.ttl = match sent.addr {
+                IpAddr::V4(addr) if addr.octets()[3] == 1 => 3,
+                IpAddr::V4(_) => 64,
+                IpAddr::V6(_) => 0,
+            };
+            Ok(vec![reply])
+        }));
+        let (test_pinger, test_channel) = mock_pinger(50, &mock);
+        test_pinger.add_ipaddr("192.0.2.1");
+        test_pinger.add_ipaddr("192.0.2.2");
+        test_pinger.add_ipaddr("2001:db8::1");
+
+        // nothing is reported without a threshold
+        test_pinger.ping_once();
+        let results: Vec<PingResult> = test_channel.try_iter().collect();
+        assert_eq!(results.len(), 3);
+        assert!(results
+            .iter()
+            .all(|r| matches!(r, PingResult::Receive { .. })));
+
+        test_pinger.set_low_ttl(Some(10));
+        test_pinger.ping_once();
+        let results: Vec<PingResult> = test_channel.try_iter().collect();
+        assert_eq!(results.len(), 4);
+        let low = results
+            .iter()
+            .position(|r| matches!(r, PingResult::LowTtl { .. }))
+            .unwrap();
+        // the event follows the target's receive, and an unknown ttl never counts as low
+        match (&results[low - 1], &results[low]) {
+            (
+                &PingResult::Receive { addr, ttl: 3, .. },
+                &PingResult::LowTtl {
+                    addr: low_addr,
+                    ttl: 3,
+                    ..
+                },
+            ) => {
+                assert_eq!(addr, "192.0.2.1".parse::<IpAddr>().unwrap());
+                assert_eq!(low_addr, addr);
             }
+            other => panic!("unexpected results {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_prune_idle() {
+        // only the first target ever replies
+        let mock = MockTransport::new(Box::new(|sent| {
+            if sent.addr == "192.0.2.1".parse::<IpAddr>().unwrap() {
+                Ok(vec![sent.reply()])
+            } else {
+                Ok(Vec::new())
+            }
+        }));
+        let (test_pinger, test_channel) = mock_pinger(50, &mock);
+        test_pinger.set_prune_idle_after(Some(Duration::from_millis(120)));
+        test_pinger.add_ipaddr("192.0.2.1");
+        test_pinger.add_ipaddr("192.0.2.2");
+        test_pinger.run_pinger();
+
+        let mut idles = 0;
+        loop {
+            match test_channel.recv_timeout(Duration::from_secs(2)) {
+                Ok(PingResult::Idle { addr, .. }) => {
+                    assert_eq!(addr, "192.0.2.2".parse::<IpAddr>().unwrap());
+                    idles += 1;
+                }
+                Ok(PingResult::Pruned { addr, .. }) => {
+                    assert_eq!(addr, "192.0.2.2".parse::<IpAddr>().unwrap());
+                    break;
+                }
+                Ok(_) => {}
+                Err(e) => panic!("target was never pruned: {}", e),
+            }
+        }
+        test_pinger.stop_pinger();
+        // pruned once idle for more than 120ms, with rounds lasting at least 50ms
+        assert!(idles >= 2);
+
+        assert_eq!(
+            test_pinger.core.lock().unwrap().targets(),
+            vec!["192.0.2.1".parse::<IpAddr>().unwrap()]
+        );
+        assert_eq!(test_pinger.identifiers.read().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_seed() {
+        fn run(seed: u64, epoch: Instant) -> (Vec<(IpAddr, Vec<u8>)>, Vec<PingResult>) {
+            let mock = MockTransport::echo();
+            let (test_pinger, test_channel) = mock_pinger(50, &mock);
+            test_pinger.set_seed(seed);
+            for host in 1..=5 {
+                test_pinger.add_ipaddr(&format!("192.0.2.{}", host));
+            }
+            test_pinger.add_ipaddr("2001:db8::1");
+            test_pinger.ping_once();
+            test_pinger.ping_once();
+
+            let probes = mock
+                .sent()
+                .into_iter()
+                .map(|s| (s.addr, s.packet))
+                .collect();
+            // rtts and emission times are measured on the clock and can't be reproduced
+            let results = test_channel
+                .try_iter()
+                .map(|result| {
+                    let mut result = match result {
+                        PingResult::Receive {
+                            addr,
+                            seq,
+                            ttl,
+                            identifier,
+                            payload_len,
+                            ..
+                        } => PingResult::Receive {
+                            addr,
+                            rtt: Duration::from_secs(0),
+                            seq,
+                            ttl,
+                            identifier,
+                            payload_len,
+                            emitted_at: epoch,
+                            timestamp: None,
+                        },
+                        other => other,
+                    };
+                    result.stamp(epoch, None);
+                    result
+                })
+                .collect();
+            (probes, results)
+        }
+
+        let epoch = Instant::now();
+        let (probes, results) = run(42, epoch);
+        assert_eq!(probes.len(), 12);
+        assert_eq!(results.len(), 12);
+        assert_eq!(run(42, epoch), (probes.clone(), results));
+        assert_ne!(run(43, epoch).0, probes);
+    }
+
+    #[test]
+    fn test_emission_monotonic() {
+        // adversarial schedules: each probe's reply is dropped, delivered once or
+        // duplicated, and every copy is delayed by up to 80ms so replies get
+        // reordered and often arrive after their round is over
+        for seed in 0..6 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mock = MockTransport::new(Box::new(move |sent| {
+                let copies = rng.gen_range(0..3);
+                Ok((0..copies)
+                    .map(|_| {
+                        let mut reply = sent.reply();
+                        reply.received_at += Duration::from_millis(rng.gen_range(0..80));
+                        reply
+                    })
+                    .collect())
+            }));
+            let (test_pinger, test_channel) = mock_pinger(30, &mock);
+            for host in 1..=4 {
+                test_pinger.add_ipaddr(&format!("192.0.2.{}", host));
+            }
+            test_pinger.run_pinger();
+            thread::sleep(Duration::from_millis(150));
+            // a second send loop racing the first, which also stops both
+            test_pinger.ping_once();
+
+            let results: Vec<PingResult> = test_channel.try_iter().collect();
+            assert!(!results.is_empty());
+            let mut last_emitted: HashMap<IpAddr, Instant> = HashMap::new();
+            let mut received = HashSet::new();
+            for (i, result) in results.iter().enumerate() {
+                if i > 0 {
+                    assert!(results[i - 1].emitted_at() <= result.emitted_at());
+                }
+                if let Some(last) = last_emitted.insert(result.addr(), result.emitted_at()) {
+                    assert!(last <= result.emitted_at());
+                }
+                if let PingResult::Receive { addr, seq, .. } = *result {
+                    assert!(received.insert((addr, seq)), "probe reported twice");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_wall_clock() {
+        let mock = MockTransport::echo();
+        let (test_pinger, test_channel) = mock_pinger(50, &mock);
+        test_pinger.add_ipaddr("192.0.2.1");
+        test_pinger.add_ipaddr("2001:db8::1");
+        test_pinger.ping_once();
+        assert!(test_channel.try_iter().all(|r| r.timestamp().is_none()));
+
+        test_pinger.set_wall_clock(true);
+        test_pinger.add_ipaddr("192.0.2.2");
+        let before = SystemTime::now();
+        test_pinger.ping_once();
+        test_pinger.ping_once();
+        let after = SystemTime::now();
+        let results: Vec<PingResult> = test_channel.try_iter().collect();
+        assert_eq!(results.len(), 6);
+        for result in &results {
+            let timestamp = result.timestamp().unwrap();
+            assert!(timestamp >= before && timestamp <= after);
+
+            let formatted = result.formatted_timestamp().unwrap();
+            let since_epoch = timestamp.duration_since(UNIX_EPOCH).unwrap();
+            assert_eq!(
+                formatted,
+                format!(
+                    "[{}.{:06}]",
+                    since_epoch.as_secs(),
+                    since_epoch.subsec_micros()
+                )
+            );
+            assert_eq!(formatted.len(), formatted.find('.').unwrap() + 8);
+        }
+        // taken in emission order, so with the clock left alone they don't go back
+        for pair in results.windows(2) {
+            assert!(pair[0].timestamp() <= pair[1].timestamp());
+        }
+    }
+
+    #[test]
+    fn test_prefix_cap_removed() {
+        // 10 probes a second put the /24's probes 100ms apart
+        let mock = MockTransport::silent();
+        let (test_pinger, _test_channel) = mock_pinger(20, &mock);
+        test_pinger.set_prefix_cap(Some(PrefixCap::new(10)));
+        for host in 1..=3 {
+            test_pinger.add_ipaddr(&format!("10.0.0.{}", host));
+        }
+        let test_pinger = Arc::new(test_pinger);
+        let mut summaries = test_pinger.round_summaries();
+        let pinger = test_pinger.clone();
+        let round = thread::spawn(move || pinger.ping_once());
+
+        // the third target goes while it waits for its slot
+        thread::sleep(Duration::from_millis(150));
+        test_pinger.remove_ipaddr("10.0.0.3");
+        round.join().unwrap();
+        assert_eq!(mock.sent().len(), 2);
+        assert_eq!(test_pinger.delayed_sends(), 1);
+        assert_eq!(summaries.next().unwrap().delayed, 1);
+    }
+
+    #[test]
+    fn test_prefix_cap() {
+        let mock = MockTransport::silent();
+        let (test_pinger, test_channel) = mock_pinger(50, &mock);
+        test_pinger.set_prefix_cap(Some(PrefixCap::new(50)));
+        let mut summaries = test_pinger.round_summaries();
+        for host in 1..=20 {
+            test_pinger.add_ipaddr(&format!("10.0.0.{}", host));
+        }
+        test_pinger.add_ipaddr("10.0.1.1");
+        test_pinger.add_ipaddr("2001:db8::1");
+        test_pinger.ping_once();
+
+        let sent = mock.sent();
+        assert_eq!(sent.len(), 22);
+        assert_eq!(test_channel.try_iter().count(), 22);
+        assert_eq!(test_pinger.delayed_sends(), 19);
+        assert_eq!(summaries.next().unwrap().delayed, 19);
+
+        // the dense /24 is paced at 50 probes a second while the other prefixes
+        // go out straight away
+        let start = sent[0].at;
+        let dense: Vec<_> = sent
+            .iter()
+            .filter(|s| PrefixCap::new(50).group(s.addr) == "10.0.0.0".parse::<IpAddr>().unwrap())
+            .collect();
+        assert_eq!(dense.len(), 20);
+        for (i, s) in dense.iter().enumerate() {
+            assert!(s.at - start >= Duration::from_millis(20) * i as u32);
+        }
+        for s in sent.iter().filter(|s| {
+            s.addr == "10.0.1.1".parse::<IpAddr>().unwrap()
+                || s.addr == "2001:db8::1".parse::<IpAddr>().unwrap()
+        }) {
+            assert!(s.at - start < Duration::from_millis(20));
         }
     }
 
@@ -429,7 +2178,7 @@ mod tests {
         // more comprehensive integration test
         match Pinger::new(None, None) {
             Ok((test_pinger, test_channel)) => {
-                let test_addrs = vec!["127.0.0.1", "7.7.7.7", "::1"];
+                let test_addrs = ["127.0.0.1", "7.7.7.7", "::1"];
                 for target in test_addrs.iter() {
                     test_pinger.add_ipaddr(target);
                 }
@@ -437,7 +2186,7 @@ mod tests {
                 for _ in test_addrs.iter() {
                     match test_channel.recv() {
                         Ok(result) => match result {
-                            PingResult::Idle { addr } => {
+                            PingResult::Idle { addr, .. } => {
                                 assert_eq!("7.7.7.7".parse::<IpAddr>().unwrap(), addr);
                             }
                             PingResult::Receive {
@@ -445,24 +2194,23 @@ mod tests {
                                 rtt: _,
                                 seq: _,
                                 ttl: _,
+                                identifier: _,
+                                payload_len: _,
+                                emitted_at: _,
+                                timestamp: _,
                             } => {
-                                if addr == "::1".parse::<IpAddr>().unwrap()
-                                    || addr == "127.0.0.1".parse::<IpAddr>().unwrap()
-                                {
-                                    assert!(true)
-                                } else {
-                                    assert!(false)
-                                }
+                                assert!(
+                                    addr == "::1".parse::<IpAddr>().unwrap()
+                                        || addr == "127.0.0.1".parse::<IpAddr>().unwrap()
+                                );
                             }
+                            other => panic!("unexpected result {:?}", other),
                         },
-                        Err(_) => assert!(false),
+                        Err(_) => panic!("client channel disconnected"),
                     }
                 }
             }
-            Err(e) => {
-                println!("Test failed: {}", e);
-                assert!(false)
-            }
+            Err(e) => panic!("Test failed: {}", e),
         }
     }
 }
@@ -1,219 +1,549 @@
+use lock::{CondvarExt, MutexExt, RwLockExt};
+use pinger_core::{MatchMode, PingerCore};
+use pnet::packet::icmp::echo_reply::EchoReplyPacket;
 use pnet::packet::icmp::echo_request;
-use pnet::packet::icmp::IcmpTypes;
+use pnet::packet::icmp::{IcmpPacket, IcmpTypes};
 use pnet::packet::icmpv6::{Icmpv6Types, MutableIcmpv6Packet};
 use pnet::packet::Packet;
-use pnet::transport::TransportSender;
 use pnet::util;
 use pnet_macros_support::types::*;
-use rand::random;
-use std::collections::BTreeMap;
+use prefix::PrefixCap;
+use rand::rngs::StdRng;
+use rand::Rng;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
 use std::net::IpAddr;
 use std::sync::mpsc::{Receiver, Sender};
-use std::sync::{Arc, Mutex, RwLock};
-use std::time::{Duration, Instant};
-use PingResult;
-
-pub struct Ping {
-    addr: IpAddr,
-    identifier: u16,
-    sequence_number: u16,
-    pub seen: bool,
-}
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+#[cfg(feature = "trace")]
+use trace::{self, Event, SharedRecorder};
+use transport::{SharedTransport, Transport};
+use {PingResult, RoundSnapshot};
 
+// an echo reply as read off the wire, before it's matched to a probe
+#[derive(Debug, Clone)]
 pub struct ReceivedPing {
     pub addr: IpAddr,
     pub identifier: u16,
     pub sequence_number: u16,
-    pub rtt: Duration,
+    pub received_at: Instant,
+    // ttl or hop limit of the reply, 0 when unknown
     pub ttl: u8,
+    pub payload_len: usize,
+    // the token the reply's payload starts with, if it has room for one
+    pub token: Option<u64>,
 }
 
-impl Ping {
-    pub fn new(addr: IpAddr) -> Ping {
-        let mut identifier = 0;
-        if addr.is_ipv4() {
-            identifier = random::<u16>();
-        }
-        Ping {
-            addr,
-            identifier,
-            sequence_number: 0,
-            seen: false,
+// identifiers in use by the ipv4 targets, counted as targets may share one.
+// ipv6 targets are left out as their probes don't carry an identifier
+pub type Identifiers = Arc<RwLock<HashMap<u16, usize>>>;
+
+pub fn track_identifier(identifiers: &Identifiers, identifier: u16) {
+    *identifiers.write_locked().entry(identifier).or_insert(0) += 1;
+}
+
+pub fn untrack_identifier(identifiers: &Identifiers, identifier: u16) {
+    let mut identifiers = identifiers.write_locked();
+    if let Some(count) = identifiers.get_mut(&identifier) {
+        *count -= 1;
+        if *count == 0 {
+            identifiers.remove(&identifier);
         }
     }
+}
 
-    pub fn new_with_seq(addr: IpAddr, seq: u16) -> Ping {
-        let mut identifier = 0;
-        if addr.is_ipv4() {
-            identifier = random::<u16>();
-        }
-        Ping {
-            addr,
-            identifier,
-            sequence_number: seq,
-            seen: false,
+// senders of every Pinger::round_summaries feed still being read
+pub type RoundFeeds = Arc<Mutex<Vec<Sender<RoundSnapshot>>>>;
+
+// a pinger's name, put at the start of its log messages so pingers sharing a
+// process can be told apart.  Unnamed pingers log without one
+#[derive(Debug, Clone, Default)]
+pub struct Name(String);
+
+impl Name {
+    pub fn new(name: &str) -> Name {
+        Name(name.to_string())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.is_empty() {
+            Ok(())
+        } else {
+            write!(f, "[{}] ", self.0)
         }
     }
+}
+
+// state shared between the pinger and the send loop
+pub struct Context {
+    pub name: Name,
+    pub size: Arc<Mutex<usize>>,
+    pub fill_byte: Arc<Mutex<u8>>,
+    pub timer: Arc<RwLock<Instant>>,
+    pub stop: Arc<Mutex<bool>>,
+    pub results_sender: Sender<PingResult>,
+    pub last_emitted: Arc<Mutex<Instant>>,
+    pub wall_clock: Arc<Mutex<bool>>,
+    pub low_ttl: Arc<Mutex<Option<u8>>>,
+    pub thread_rx: Arc<Mutex<Receiver<ReceivedPing>>>,
+    pub tx: SharedTransport,
+    pub txv6: SharedTransport,
+    pub core: Arc<Mutex<PingerCore>>,
+    pub targets_changed: Arc<Condvar>,
+    pub rng: Arc<Mutex<StdRng>>,
+    pub prewarm: Arc<Mutex<bool>>,
+    pub prefix_cap: Arc<Mutex<Option<PrefixCap>>>,
+    pub delayed_sends: Arc<Mutex<u64>>,
+    pub round_feeds: RoundFeeds,
+    #[cfg(feature = "trace")]
+    pub trace: SharedRecorder,
+}
 
-    pub fn get_addr(&self) -> IpAddr {
-        return self.addr;
+// identifier for a new target's probes.  ipv6 probes don't carry one
+pub fn new_identifier<R: Rng>(addr: IpAddr, rng: &mut R) -> u16 {
+    if addr.is_ipv4() {
+        rng.gen::<u16>()
+    } else {
+        0
     }
+}
 
-    pub fn get_identifier(&self) -> u16 {
-        return self.identifier;
+// the ipv4 address an ipv4-mapped ipv6 address (::ffff:a.b.c.d) stands for, any
+// other address as it is.  Mapped addresses can't be reached over icmpv6, so
+// they're pinged as the ipv4 targets they map
+pub fn unmapped(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(addr, IpAddr::V4),
+        IpAddr::V4(_) => addr,
     }
+}
+
+// smallest packet size a probe can be sent with, the icmp echo request header
+pub const MIN_PACKET_SIZE: usize = 8;
 
-    pub fn get_sequence_number(&self) -> u16 {
-        return self.sequence_number;
+// smallest packet size a probe carrying a payload token can be sent with
+pub const TOKEN_PACKET_SIZE: usize = MIN_PACKET_SIZE + 8;
+
+// the token at the start of an echo payload
+pub fn payload_token(payload: &[u8]) -> Option<u64> {
+    if payload.len() < 8 {
+        return None;
     }
+    let mut token = [0; 8];
+    token.copy_from_slice(&payload[..8]);
+    Some(u64::from_be_bytes(token))
+}
 
-    pub fn increment_sequence_number(&mut self) -> u16 {
-        self.sequence_number += 1;
-        return self.sequence_number;
+// allocate a probe with its payload filled with the fill byte, and the token at the
+// start of the payload if one is given
+fn new_probe(size: usize, token: Option<u64>, fill: u8) -> io::Result<Vec<u8>> {
+    if size < MIN_PACKET_SIZE {
+        return Err(packet_too_small(size));
+    }
+    let mut vec: Vec<u8> = vec![0; size];
+    for byte in &mut vec[MIN_PACKET_SIZE..] {
+        *byte = fill;
+    }
+    if let Some(token) = token {
+        if size < TOKEN_PACKET_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "packet size {} has no room for a payload token ({} bytes needed)",
+                    size, TOKEN_PACKET_SIZE
+                ),
+            ));
+        }
+        vec[MIN_PACKET_SIZE..TOKEN_PACKET_SIZE].copy_from_slice(&token.to_be_bytes());
     }
+    Ok(vec)
 }
 
 fn send_echo(
-    tx: &mut TransportSender,
-    ping: &mut Ping,
+    tx: &mut dyn Transport,
+    addr: IpAddr,
+    identifier: u16,
+    seq: u16,
+    token: Option<u64>,
     size: usize,
+    fill: u8,
 ) -> Result<usize, std::io::Error> {
     // Allocate enough space for a new packet
-    let mut vec: Vec<u8> = vec![0; size];
+    let mut vec = new_probe(size, token, fill)?;
 
     // Use echo_request so we can set the identifier and sequence number
-    let mut echo_packet = echo_request::MutableEchoRequestPacket::new(&mut vec[..]).unwrap();
-    echo_packet.set_sequence_number(ping.increment_sequence_number());
-    echo_packet.set_identifier(ping.get_identifier());
+    let mut echo_packet = echo_request::MutableEchoRequestPacket::new(&mut vec[..])
+        .expect("buffer holds an echo request header");
+    echo_packet.set_sequence_number(seq);
+    echo_packet.set_identifier(identifier);
     echo_packet.set_icmp_type(IcmpTypes::EchoRequest);
 
     let csum = icmp_checksum(&echo_packet);
     echo_packet.set_checksum(csum);
 
-    tx.send_to(echo_packet, ping.get_addr())
+    tx.send_to(echo_packet.packet(), addr)
+}
+
+fn packet_too_small(size: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!(
+            "packet size {} is smaller than an echo request header ({} bytes)",
+            size, MIN_PACKET_SIZE
+        ),
+    )
 }
 
 fn send_echov6(
-    tx: &mut TransportSender,
+    tx: &mut dyn Transport,
     addr: IpAddr,
+    token: Option<u64>,
     size: usize,
+    fill: u8,
 ) -> Result<usize, std::io::Error> {
     // Allocate enough space for a new packet
-    let mut vec: Vec<u8> = vec![0; size];
+    let mut vec = new_probe(size, token, fill)?;
 
-    let mut echo_packet = MutableIcmpv6Packet::new(&mut vec[..]).unwrap();
+    let mut echo_packet =
+        MutableIcmpv6Packet::new(&mut vec[..]).expect("buffer holds an echo request header");
     echo_packet.set_icmpv6_type(Icmpv6Types::EchoRequest);
 
     let csum = icmpv6_checksum(&echo_packet);
     echo_packet.set_checksum(csum);
 
-    tx.send_to(echo_packet, addr)
+    tx.send_to(echo_packet.packet(), addr)
 }
 
-pub fn send_pings(
-    size: usize,
-    timer: Arc<RwLock<Instant>>,
-    stop: Arc<Mutex<bool>>,
-    results_sender: Sender<PingResult>,
-    thread_rx: Arc<Mutex<Receiver<ReceivedPing>>>,
-    tx: Arc<Mutex<TransportSender>>,
-    txv6: Arc<Mutex<TransportSender>>,
-    targets: Arc<Mutex<BTreeMap<IpAddr, Ping>>>,
-    max_rtt: Arc<Duration>,
-) {
+// parse an icmp packet received by the ipv4 listener into a reply for the matcher.
+// The identifier sits at the same offset in every echo reply, so it's checked
+// against the tracked identifiers before anything else is parsed to cheaply
+// drop unrelated icmp traffic on busy hosts.  Without identifiers every echo reply
+// is parsed, for when the identifier can't be relied on
+pub fn parse_echo_reply(
+    packet: &[u8],
+    addr: IpAddr,
+    received_at: Instant,
+    identifiers: Option<&Identifiers>,
+    name: &Name,
+) -> Option<ReceivedPing> {
+    if packet.len() < EchoReplyPacket::minimum_packet_size() {
+        return None;
+    }
+    let identifier = u16::from_be_bytes([packet[4], packet[5]]);
+    if let Some(identifiers) = identifiers {
+        if !identifiers.read_locked().contains_key(&identifier) {
+            return None;
+        }
+    }
+
+    let icmp_packet = IcmpPacket::new(packet)?;
+    if icmp_packet.get_icmp_type() != IcmpTypes::EchoReply {
+        debug!(
+            "{}ICMP type other than reply (0) received from {:?}: {:?}",
+            name,
+            addr,
+            icmp_packet.get_icmp_type()
+        );
+        return None;
+    }
+    let echo_reply = EchoReplyPacket::new(packet)?;
+    Some(ReceivedPing {
+        addr,
+        identifier,
+        sequence_number: echo_reply.get_sequence_number(),
+        received_at,
+        // the ttl is in the ip header, which the listener fills it in from
+        ttl: 0,
+        payload_len: echo_reply.payload().len(),
+        token: payload_token(echo_reply.payload()),
+    })
+}
+
+impl Context {
+    // stamp a result with its emission time and hand it to the client.  Stamping
+    // and sending happen under one lock, so even with several send loops running
+    // results come out of the channel in the order they were stamped.  Returns
+    // the result as stamped
+    fn emit(&self, mut result: PingResult) -> PingResult {
+        let mut last_emitted = self.last_emitted.locked();
+        let now = Instant::now().max(*last_emitted);
+        *last_emitted = now;
+        let timestamp = if *self.wall_clock.locked() {
+            Some(SystemTime::now())
+        } else {
+            None
+        };
+        result.stamp(now, timestamp);
+        #[cfg(feature = "trace")]
+        trace::record(&self.trace, now, Event::Result(result.clone()));
+        if let Err(e) = self.results_sender.send(result.clone()) {
+            if !*self.stop.locked() {
+                error!("{}Error sending ping result on channel: {}", self.name, e)
+            }
+        }
+        result
+    }
+
+    // the LowTtl to follow a Receive with, if its ttl is known and below the threshold
+    fn low_ttl(&self, result: &PingResult) -> Option<PingResult> {
+        let threshold = (*self.low_ttl.locked())?;
+        match *result {
+            PingResult::Receive { addr, ttl, .. } if ttl != 0 && ttl < threshold => {
+                Some(PingResult::LowTtl {
+                    addr,
+                    ttl,
+                    // stamped when emitted
+                    emitted_at: Instant::now(),
+                    timestamp: None,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    // hand a completed round to every round_summaries feed, forgetting the
+    // feeds whose iterator was dropped
+    fn publish(&self, snapshot: RoundSnapshot) {
+        self.round_feeds
+            .locked()
+            .retain(|feed| feed.send(snapshot.clone()).is_ok());
+    }
+}
+
+pub fn send_pings(ctx: Context) {
+    let mut prewarm = *ctx.prewarm.locked();
     loop {
-        for (addr, ping) in targets.lock().unwrap().iter_mut() {
-            match if addr.is_ipv4() {
-                send_echo(&mut tx.lock().unwrap(), ping, size)
-            } else if addr.is_ipv6() {
-                send_echov6(&mut txv6.lock().unwrap(), *addr, size)
-            } else {
-                Ok(0)
-            } {
-                Err(e) => error!("Failed to send ping to {:?}: {}", *addr, e),
-                _ => {}
+        if !wait_for_targets(&ctx) {
+            return;
+        }
+        if prewarm {
+            // send a throwaway probe to every target so neighbor caches are populated
+            // before the first measured round
+            debug!("{}Prewarming {} targets", ctx.name, ctx.core.locked().len());
+            run_round(&ctx, false);
+            prewarm = false;
+        }
+        run_round(&ctx, true);
+        // check if we've received the stop signal
+        if *ctx.stop.locked() {
+            return;
+        }
+    }
+}
+
+// block while there are no targets rather than running empty rounds, until one
+// is added or the pinger is stopped.  Returns whether there are targets to ping
+fn wait_for_targets(ctx: &Context) -> bool {
+    let mut core = ctx.core.locked();
+    while core.is_empty() {
+        if *ctx.stop.locked() {
+            return false;
+        }
+        core = ctx.targets_changed.wait_locked(core);
+    }
+    true
+}
+
+// send the next probe to a target, unless it was removed while waiting for its
+// turn.  Returns whether a probe was sent
+fn send_probe(ctx: &Context, addr: IpAddr, retry: bool) -> bool {
+    // held with the core so the size and match mode can't change apart under the probe
+    let size = ctx.size.locked();
+    let mut core = ctx.core.locked();
+    let (identifier, seq) = match (core.identifier(addr), core.sequence_number(addr)) {
+        (Some(identifier), Some(seq)) => (identifier, seq),
+        _ => return false,
+    };
+    // ipv6 probes go out without a sequence number, so the target's stays put
+    let seq = if addr.is_ipv4() {
+        seq.wrapping_add(1)
+    } else {
+        seq
+    };
+    let sent_at = Instant::now();
+    let token = match core.match_mode() {
+        MatchMode::Header => None,
+        MatchMode::PayloadToken => Some(ctx.rng.locked().gen::<u64>()),
+    };
+    let tracked = match (retry, token) {
+        (true, _) => core.on_retry(addr, seq, token, sent_at),
+        (false, None) => core.on_send(addr, seq, sent_at),
+        (false, Some(token)) => core.on_send_with_token(addr, seq, token, sent_at),
+    };
+    // a target can reply while its retry is on the way
+    if !tracked {
+        return false;
+    }
+    let fill = *ctx.fill_byte.locked();
+    let sent = if addr.is_ipv4() {
+        send_echo(
+            &mut **ctx.tx.locked(),
+            addr,
+            identifier,
+            seq,
+            token,
+            *size,
+            fill,
+        )
+    } else {
+        send_echov6(&mut **ctx.txv6.locked(), addr, token, *size, fill)
+    };
+    core.on_send_result(addr, sent.is_ok());
+    if let Err(e) = sent {
+        error!("{}Failed to send ping to {:?}: {}", ctx.name, addr, e);
+    }
+    #[cfg(feature = "trace")]
+    trace::record(
+        &ctx.trace,
+        sent_at,
+        if retry {
+            Event::Retry {
+                addr,
+                identifier,
+                seq,
+                token,
             }
-            ping.seen = false;
+        } else {
+            Event::Probe {
+                addr,
+                identifier,
+                seq,
+                token,
+            }
+        },
+    );
+    true
+}
+
+// send a probe to every target, spread out per the prefix cap if one is set, and
+// wait up to max_rtt for the replies.  Results are only handed to the client if
+// emit is set
+fn run_round(ctx: &Context, emit: bool) {
+    let schedule = {
+        let targets = ctx.core.locked().targets();
+        match *ctx.prefix_cap.locked() {
+            Some(cap) => cap.schedule(targets),
+            None => targets
+                .into_iter()
+                .map(|addr| (Duration::from_secs(0), addr))
+                .collect(),
         }
-        {
-            // start the timer
-            let mut timer = timer.write().unwrap();
-            *timer = Instant::now();
-        }
-        loop {
-            // use recv_timeout so we don't cause a CPU to needlessly spin
-            match thread_rx
-                .lock()
-                .unwrap()
-                .recv_timeout(Duration::from_millis(100))
-            {
-                Ok(ping_result) => {
-                    match ping_result {
-                        ReceivedPing {
-                            addr,
-                            identifier,
-                            sequence_number,
-                            rtt,
-                            ttl,
-                        } => {
-                            // Update the address to the ping response being received
-                            if let Some(ping) = targets.lock().unwrap().get_mut(&addr) {
-                                if ping.get_identifier() == identifier
-                                    && ping.get_sequence_number() == sequence_number
-                                {
-                                    ping.seen = true;
-                                    // Send the ping result over the client channel
-                                    match results_sender.send(PingResult::Receive {
-                                        addr: ping_result.addr,
-                                        rtt,
-                                        seq: sequence_number,
-                                        ttl,
-                                    }) {
-                                        Ok(_) => {}
-                                        Err(e) => {
-                                            if !*stop.lock().unwrap() {
-                                                error!(
-                                                    "Error sending ping result on channel: {}",
-                                                    e
-                                                )
-                                            }
-                                        }
-                                    }
-                                } else {
-                                    debug!("Received echo reply from target {}, but sequence_number (expected {} but got {}) and identifier (expected {} but got {}) don't match", addr, ping.get_sequence_number(), sequence_number, ping.get_identifier(), identifier);
-                                }
-                            }
-                        }
+    };
+    let start = Instant::now();
+    let mut targets = 0;
+    let mut retries = 0;
+    let mut delayed = 0;
+    for (due, addr) in schedule {
+        let held_back = due > Duration::from_secs(0);
+        if held_back {
+            let elapsed = start.elapsed();
+            if due > elapsed {
+                thread::sleep(due - elapsed);
+            }
+        }
+        // a target removed while waiting for its slot isn't probed, nor counted
+        if send_probe(ctx, addr, false) {
+            targets += 1;
+            if held_back {
+                *ctx.delayed_sends.locked() += 1;
+                delayed += 1;
+            }
+        }
+    }
+    {
+        // start the timer
+        let mut timer = ctx.timer.write_locked();
+        *timer = Instant::now();
+    }
+    let mut results = Vec::new();
+    loop {
+        // probe the targets which haven't replied in time again, if retries are on
+        let due = ctx.core.locked().retries_due(Instant::now());
+        for addr in due {
+            if send_probe(ctx, addr, true) {
+                retries += 1;
+            }
+        }
+        // use recv_timeout so we don't cause a CPU to needlessly spin, waking up
+        // early for the next retry
+        let poll = Duration::from_millis(100);
+        let timeout = ctx.core.locked().next_retry().map_or(poll, |next| {
+            next.saturating_duration_since(Instant::now()).min(poll)
+        });
+        match ctx.thread_rx.locked().recv_timeout(timeout) {
+            Ok(reply) => {
+                // Update the address to the ping response being received
+                let mut core = ctx.core.locked();
+                #[cfg(feature = "trace")]
+                {
+                    if emit {
+                        trace::record(&ctx.trace, reply.received_at, Event::from(&reply));
                     }
                 }
-                Err(_) => {
-                    // Check we haven't exceeded the max rtt
-                    let start_time = timer.read().unwrap();
-                    if Instant::now().duration_since(*start_time) > *max_rtt {
-                        break;
+                // replies to an unmeasured round don't count towards the statistics
+                let result = if emit {
+                    core.on_reply(&reply, reply.received_at)
+                } else {
+                    core.on_unmeasured_reply(&reply, reply.received_at)
+                };
+                if let Some(result) = result {
+                    if emit {
+                        let low_ttl = ctx.low_ttl(&result);
+                        // Send the ping result over the client channel
+                        results.push(ctx.emit(result));
+                        if let Some(low_ttl) = low_ttl {
+                            results.push(ctx.emit(low_ttl));
+                        }
                     }
                 }
             }
-        }
-        // check for addresses which haven't replied
-        for (addr, ping) in targets.lock().unwrap().iter() {
-            if ping.seen == false {
-                // Send the ping Idle over the client channel
-                match results_sender.send(PingResult::Idle { addr: *addr }) {
-                    Ok(_) => {}
-                    Err(e) => {
-                        if !*stop.lock().unwrap() {
-                            error!("Error sending ping Idle result on channel: {}", e)
-                        }
-                    }
+            Err(_) => {
+                // Check we haven't exceeded the max rtt
+                let start_time = ctx.timer.read_locked();
+                if Instant::now().duration_since(*start_time) > ctx.core.locked().max_rtt() {
+                    break;
                 }
             }
         }
-        // check if we've received the stop signal
-        if *stop.lock().unwrap() {
-            return;
+    }
+    if !emit {
+        return;
+    }
+    // check for addresses which haven't replied
+    let mut core = ctx.core.locked();
+    let now = Instant::now();
+    // another send loop may have probed since, in which case the round isn't over yet
+    let ended = core.deadline().is_some_and(|deadline| now >= deadline);
+    #[cfg(feature = "trace")]
+    {
+        if ended {
+            trace::record(&ctx.trace, now, Event::RoundEnd);
         }
     }
+    for result in core.tick(now) {
+        // Send the ping Idle over the client channel
+        results.push(ctx.emit(result));
+    }
+    if ended {
+        ctx.publish(RoundSnapshot {
+            started_at: start,
+            ended_at: now,
+            sent: targets + retries,
+            targets,
+            retries,
+            delayed,
+            results,
+        });
+    }
 }
 
 fn icmp_checksum(packet: &echo_request::MutableEchoRequestPacket) -> u16be {
@@ -227,14 +557,183 @@ fn icmpv6_checksum(packet: &MutableIcmpv6Packet) -> u16be {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use rand::SeedableRng;
+
+    fn echo_reply(identifier: u16, seq: u16) -> Vec<u8> {
+        let mut vec: Vec<u8> = vec![0; 16];
+        {
+            let mut echo_packet =
+                echo_request::MutableEchoRequestPacket::new(&mut vec[..]).unwrap();
+            echo_packet.set_icmp_type(IcmpTypes::EchoReply);
+            echo_packet.set_identifier(identifier);
+            echo_packet.set_sequence_number(seq);
+        }
+        vec
+    }
+
+    #[test]
+    fn test_parse_echo_reply() {
+        let addr = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let identifiers: Identifiers = Arc::new(RwLock::new(HashMap::new()));
+        track_identifier(&identifiers, 7);
+
+        let reply = parse_echo_reply(
+            &echo_reply(7, 3),
+            addr,
+            Instant::now(),
+            Some(&identifiers),
+            &Name::default(),
+        )
+        .unwrap();
+        assert_eq!(reply.identifier, 7);
+        assert_eq!(reply.sequence_number, 3);
+        assert_eq!(reply.payload_len, 8);
+
+        // untracked identifiers, non-replies and truncated packets are dropped
+        assert!(parse_echo_reply(
+            &echo_reply(8, 3),
+            addr,
+            Instant::now(),
+            Some(&identifiers),
+            &Name::default()
+        )
+        .is_none());
+        let mut request = echo_reply(7, 3);
+        request[0] = 8;
+        assert!(parse_echo_reply(
+            &request,
+            addr,
+            Instant::now(),
+            Some(&identifiers),
+            &Name::default()
+        )
+        .is_none());
+        assert!(parse_echo_reply(
+            &echo_reply(7, 3)[..6],
+            addr,
+            Instant::now(),
+            Some(&identifiers),
+            &Name::default()
+        )
+        .is_none());
+
+        // an identifier stays tracked until every target using it is gone
+        track_identifier(&identifiers, 7);
+        untrack_identifier(&identifiers, 7);
+        assert!(parse_echo_reply(
+            &echo_reply(7, 3),
+            addr,
+            Instant::now(),
+            Some(&identifiers),
+            &Name::default()
+        )
+        .is_some());
+        untrack_identifier(&identifiers, 7);
+        assert!(parse_echo_reply(
+            &echo_reply(7, 3),
+            addr,
+            Instant::now(),
+            Some(&identifiers),
+            &Name::default()
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn test_parse_payload_token() {
+        let addr = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let identifiers: Identifiers = Arc::new(RwLock::new(HashMap::new()));
+        let mut packet = new_probe(24, Some(0x0102_0304_0506_0708), 0).unwrap();
+        packet[0] = 0;
+        packet[4] = 9;
+
+        // without identifiers, replies carrying any identifier get through
+        assert!(parse_echo_reply(
+            &packet,
+            addr,
+            Instant::now(),
+            Some(&identifiers),
+            &Name::default()
+        )
+        .is_none());
+        let reply =
+            parse_echo_reply(&packet, addr, Instant::now(), None, &Name::default()).unwrap();
+        assert_eq!(reply.token, Some(0x0102_0304_0506_0708));
+        assert_eq!(reply.payload_len, 16);
+
+        // the token needs the first 8 bytes of payload
+        assert_eq!(
+            parse_echo_reply(
+                &echo_reply(7, 3),
+                addr,
+                Instant::now(),
+                None,
+                &Name::default()
+            )
+            .unwrap()
+            .token,
+            Some(0)
+        );
+        assert_eq!(
+            parse_echo_reply(
+                &echo_reply(7, 3)[..12],
+                addr,
+                Instant::now(),
+                None,
+                &Name::default()
+            )
+            .unwrap()
+            .token,
+            None
+        );
+        assert!(new_probe(15, Some(1), 0).is_err());
+        assert!(new_probe(15, None, 0).is_ok());
+    }
+
+    #[test]
+    fn test_parse_echo_reply_unrelated_traffic() {
+        // with ten tracked targets among a thousand busy flows, only the tracked
+        // replies make it through to the matcher
+        let addr = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let identifiers: Identifiers = Arc::new(RwLock::new(HashMap::new()));
+        for identifier in 0..10 {
+            track_identifier(&identifiers, identifier * 100);
+        }
+        let packets: Vec<Vec<u8>> = (0..1000)
+            .map(|identifier| echo_reply(identifier, 1))
+            .collect();
+        let matched = packets
+            .iter()
+            .filter_map(|packet| {
+                parse_echo_reply(
+                    packet,
+                    addr,
+                    Instant::now(),
+                    Some(&identifiers),
+                    &Name::default(),
+                )
+            })
+            .count();
+        assert_eq!(matched, 10);
+    }
 
     #[test]
-    fn test_ping() {
-        let mut p = Ping::new("127.0.0.1".parse::<IpAddr>().unwrap());
-        assert_eq!(p.get_sequence_number(), 0);
-        assert!(p.get_identifier() > 0);
+    fn test_unmapped() {
+        let addr = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert_eq!(unmapped(addr("::ffff:192.0.2.1")), addr("192.0.2.1"));
+        assert_eq!(unmapped(addr("192.0.2.1")), addr("192.0.2.1"));
+        assert_eq!(unmapped(addr("2001:db8::1")), addr("2001:db8::1"));
+        // ipv4-compatible addresses are deprecated and stay ipv6
+        assert_eq!(unmapped(addr("::192.0.2.1")), addr("::192.0.2.1"));
+    }
 
-        p.increment_sequence_number();
-        assert_eq!(p.get_sequence_number(), 1);
+    #[test]
+    fn test_new_identifier() {
+        let mut rng = StdRng::from_entropy();
+        assert!(new_identifier("127.0.0.1".parse::<IpAddr>().unwrap(), &mut rng) > 0);
+        assert_eq!(
+            new_identifier("::1".parse::<IpAddr>().unwrap(), &mut rng),
+            0
+        );
     }
 }
@@ -0,0 +1,953 @@
+use lock::RwLockExt;
+use ping::{track_identifier, untrack_identifier, Identifiers, Name, ReceivedPing};
+use quality::{Outcomes, TargetStats};
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use PingResult;
+
+// how replies are matched to the probes they answer
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MatchMode {
+    // by source address, identifier and sequence number.  The default
+    Header,
+    // by a random token carried in the payload of each probe, whatever the source
+    // address, identifier and sequence number of the reply.  For networks with
+    // middleboxes rewriting the icmp header.  Probes need room for the token, at
+    // least TOKEN_PACKET_SIZE bytes
+    PayloadToken,
+}
+
+// what to do when a new ipv4 target draws an identifier another target already
+// uses.  Replies are matched by address too, so targets sharing an identifier are
+// still told apart, but the listener's early identifier check then lets through
+// replies meant for either
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IdentifierPolicy {
+    // keep the identifier and share it
+    Share,
+    // draw identifiers until one is free, sharing only once all of them are taken.
+    // The default
+    Reroll,
+    // refuse to add the target
+    Reject,
+}
+
+struct Ping {
+    identifier: u16,
+    sequence_number: u16,
+    token: Option<u64>,
+    sent_at: Option<Instant>,
+    // the round's earlier probes, superseded by retries but still answerable, as
+    // their sequence number, token and send time
+    retried: Vec<(u16, Option<u64>, Instant)>,
+    // whether the latest probe made it onto the wire, None before the first send
+    last_send_ok: Option<bool>,
+    last_seen: Instant,
+    seen: bool,
+    stats: TargetStats,
+    outcomes: Outcomes,
+}
+
+// the pinger's bookkeeping without any threads, sockets or clocks: the targets,
+// their outstanding probes, matching replies to them and deciding when a round
+// is over.  The caller does the sending and receiving and tells the core about
+// it, along with the time it happened.  Pinger drives one of these, and it can
+// be driven directly from any event loop or test.
+//
+// Pinger keeps going with a core whose lock was poisoned, which is only safe as
+// long as no method panics halfway through its changes: they run no transport
+// or client code, and stick to map and vector operations, assignments and
+// checked time arithmetic
+pub struct PingerCore {
+    name: Name,
+    targets: BTreeMap<IpAddr, Ping>,
+    identifiers: Identifiers,
+    match_mode: MatchMode,
+    // tokens of the outstanding probes and the targets they were sent to
+    tokens: HashMap<u64, IpAddr>,
+    max_rtt: Duration,
+    prune_after: Option<Duration>,
+    retries: u8,
+    // when the latest probe of the round was sent
+    last_send: Option<Instant>,
+}
+
+impl PingerCore {
+    pub fn new(max_rtt: Duration) -> PingerCore {
+        PingerCore {
+            name: Name::default(),
+            targets: BTreeMap::new(),
+            identifiers: Arc::new(RwLock::new(HashMap::new())),
+            match_mode: MatchMode::Header,
+            tokens: HashMap::new(),
+            max_rtt,
+            prune_after: None,
+            retries: 0,
+            last_send: None,
+        }
+    }
+
+    // label the core's log messages with the name of the pinger it belongs to
+    pub fn set_name(&mut self, name: &str) {
+        self.name = Name::new(name);
+    }
+
+    pub fn max_rtt(&self) -> Duration {
+        self.max_rtt
+    }
+
+    // how long after the round's latest probe the round ends, the round in
+    // progress included
+    pub fn set_max_rtt(&mut self, max_rtt: Duration) {
+        self.max_rtt = max_rtt;
+    }
+
+    // remove targets which haven't replied for longer than the given duration when
+    // a round ends.  None, the default, never prunes
+    pub fn set_prune_after(&mut self, after: Option<Duration>) {
+        self.prune_after = after;
+    }
+
+    pub fn retries_per_round(&self) -> u8 {
+        self.retries
+    }
+
+    // how many more probes a target which hasn't replied is sent within a round
+    // before it's reported idle, see retries_due.  0, the default, never retries
+    pub fn set_retries_per_round(&mut self, retries: u8) {
+        self.retries = retries;
+    }
+
+    pub fn match_mode(&self) -> MatchMode {
+        self.match_mode
+    }
+
+    pub fn set_match_mode(&mut self, mode: MatchMode) {
+        self.match_mode = mode;
+    }
+
+    // identifiers in use by the ipv4 targets, kept up to date as targets come and go
+    pub fn identifiers(&self) -> Identifiers {
+        self.identifiers.clone()
+    }
+
+    // start tracking a target, replacing any target already at the address.  seq is
+    // the sequence number the target's last probe was sent with, at counts as the
+    // last time it was seen.  ipv6 probes carry no identifier, so pass 0 for them
+    pub fn add_target(&mut self, addr: IpAddr, identifier: u16, seq: u16, at: Instant) {
+        if addr.is_ipv4() {
+            track_identifier(&self.identifiers, identifier);
+        }
+        let ping = Ping {
+            identifier,
+            sequence_number: seq,
+            token: None,
+            sent_at: None,
+            retried: Vec::new(),
+            last_send_ok: None,
+            last_seen: at,
+            seen: false,
+            stats: TargetStats::default(),
+            outcomes: Outcomes::default(),
+        };
+        if let Some(old) = self.targets.insert(addr, ping) {
+            self.untrack(addr, &old);
+        }
+    }
+
+    // stop tracking a target.  Returns whether it was tracked
+    pub fn remove_target(&mut self, addr: IpAddr) -> bool {
+        match self.targets.remove(&addr) {
+            Some(old) => {
+                self.untrack(addr, &old);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn untrack(&mut self, addr: IpAddr, ping: &Ping) {
+        if addr.is_ipv4() {
+            untrack_identifier(&self.identifiers, ping.identifier);
+        }
+        if let Some(token) = ping.token {
+            self.tokens.remove(&token);
+        }
+        for &(_, token, _) in &ping.retried {
+            if let Some(token) = token {
+                self.tokens.remove(&token);
+            }
+        }
+    }
+
+    pub fn targets(&self) -> Vec<IpAddr> {
+        self.targets.keys().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.targets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.targets.is_empty()
+    }
+
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        self.targets.contains_key(&addr)
+    }
+
+    pub fn identifier(&self, addr: IpAddr) -> Option<u16> {
+        self.targets.get(&addr).map(|ping| ping.identifier)
+    }
+
+    // whether an ipv4 target other than the one at addr uses the identifier.  ipv6
+    // probes carry no identifier, so an ipv6 target never collides
+    pub fn identifier_taken(&self, addr: IpAddr, identifier: u16) -> bool {
+        if !addr.is_ipv4() {
+            return false;
+        }
+        let users = self
+            .identifiers
+            .read_locked()
+            .get(&identifier)
+            .cloned()
+            .unwrap_or(0);
+        let own = self.identifier(addr) == Some(identifier);
+        users > own as usize
+    }
+
+    // whether any identifier is left unused by the ipv4 targets
+    pub fn identifiers_free(&self) -> bool {
+        self.identifiers.read_locked().len() <= usize::from(u16::MAX)
+    }
+
+    // the sequence number of the target's latest probe
+    pub fn sequence_number(&self, addr: IpAddr) -> Option<u16> {
+        self.targets.get(&addr).map(|ping| ping.sequence_number)
+    }
+
+    // the target's reply statistics since it was added
+    pub fn stats(&self, addr: IpAddr) -> Option<TargetStats> {
+        self.targets.get(&addr).map(|ping| ping.stats)
+    }
+
+    // fraction of the target's probes sent within the window before now which went
+    // unanswered.  A window reaching back before the target was added, or further
+    // than the last MAX_OUTCOMES probes, covers only the probes there are.  None for
+    // an unknown target or if no probe in the window has had its round end
+    pub fn windowed_loss(&self, addr: IpAddr, window: Duration, now: Instant) -> Option<f64> {
+        let ping = self.targets.get(&addr)?;
+        ping.outcomes.loss_since(now.checked_sub(window))
+    }
+
+    // a probe with the given sequence number was sent to the target, replacing its
+    // outstanding probe.  Returns false if the target isn't tracked
+    pub fn on_send(&mut self, addr: IpAddr, seq: u16, at: Instant) -> bool {
+        match self.targets.get_mut(&addr) {
+            Some(ping) => {
+                if let Some(token) = ping.token.take() {
+                    self.tokens.remove(&token);
+                }
+                for (_, token, _) in ping.retried.drain(..) {
+                    if let Some(token) = token {
+                        self.tokens.remove(&token);
+                    }
+                }
+                ping.sequence_number = seq;
+                ping.sent_at = Some(at);
+                ping.seen = false;
+                self.last_send = Some(self.last_send.map_or(at, |last| last.max(at)));
+                true
+            }
+            None => false,
+        }
+    }
+
+    // a probe carrying the given payload token was sent to the target, see
+    // MatchMode::PayloadToken.  Returns false if the target isn't tracked
+    pub fn on_send_with_token(&mut self, addr: IpAddr, seq: u16, token: u64, at: Instant) -> bool {
+        if !self.on_send(addr, seq, at) {
+            return false;
+        }
+        if let Some(ping) = self.targets.get_mut(&addr) {
+            ping.token = Some(token);
+        }
+        self.tokens.insert(token, addr);
+        true
+    }
+
+    // a retry with the given sequence number, and token when matching by token, was
+    // sent to a target whose probe is outstanding.  Replies to the round's earlier
+    // probes still count.  Unlike on_send this leaves the round's deadline alone, so
+    // retries don't stretch the round.  Returns false if the target has no
+    // outstanding probe
+    pub fn on_retry(&mut self, addr: IpAddr, seq: u16, token: Option<u64>, at: Instant) -> bool {
+        let ping = match self.targets.get_mut(&addr) {
+            Some(ping) if !ping.seen => ping,
+            _ => return false,
+        };
+        let sent_at = match ping.sent_at {
+            Some(sent_at) => sent_at,
+            None => return false,
+        };
+        ping.retried
+            .push((ping.sequence_number, ping.token, sent_at));
+        ping.sequence_number = seq;
+        ping.token = token;
+        ping.sent_at = Some(at);
+        if let Some(token) = token {
+            self.tokens.insert(token, addr);
+        }
+        true
+    }
+
+    // how long a probe waits for its reply before the target is retried, the
+    // round's max_rtt split evenly between the first probe and its retries
+    fn retry_after(&self) -> Duration {
+        self.max_rtt / (u32::from(self.retries) + 1)
+    }
+
+    // targets with retries left which haven't replied, and when each is due its
+    // next retry.  ipv6 probes all go out with the same header, so a reply couldn't
+    // be told to answer the probe or its retry, and ipv6 targets are only retried
+    // when matching by payload token
+    fn pending_retries(&self) -> impl Iterator<Item = (IpAddr, Instant)> + '_ {
+        let retry_after = self.retry_after();
+        let distinct = self.match_mode == MatchMode::PayloadToken;
+        self.targets.iter().filter_map(move |(&addr, ping)| {
+            let sent_at = ping.sent_at?;
+            if ping.seen
+                || ping.retried.len() >= usize::from(self.retries)
+                || !(addr.is_ipv4() || distinct)
+            {
+                return None;
+            }
+            Some((addr, sent_at.checked_add(retry_after)?))
+        })
+    }
+
+    // the targets due a retry, whose latest probe of the round has gone unanswered
+    // for max_rtt / (retries_per_round + 1)
+    pub fn retries_due(&self, now: Instant) -> Vec<IpAddr> {
+        self.pending_retries()
+            .filter(|&(_, due)| now >= due)
+            .map(|(addr, _)| addr)
+            .collect()
+    }
+
+    // when the next retry falls due, None if no target is waiting for one
+    pub fn next_retry(&self) -> Option<Instant> {
+        self.pending_retries().map(|(_, due)| due).min()
+    }
+
+    // the transport accepted or refused the target's latest probe.  A refused probe
+    // stays outstanding, so a target failing to send still goes idle
+    pub fn on_send_result(&mut self, addr: IpAddr, ok: bool) {
+        if let Some(ping) = self.targets.get_mut(&addr) {
+            ping.last_send_ok = Some(ok);
+        }
+    }
+
+    // whether the target's latest probe made it onto the wire.  None for an unknown
+    // target or before its first probe
+    pub fn last_send_ok(&self, addr: IpAddr) -> Option<bool> {
+        self.targets.get(&addr)?.last_send_ok
+    }
+
+    // an echo reply was received at the given time.  Returns the result to hand to
+    // the client if the reply answers the outstanding probe of a target for the
+    // first time
+    pub fn on_reply(&mut self, reply: &ReceivedPing, at: Instant) -> Option<PingResult> {
+        self.answer(reply, at, true)
+    }
+
+    // like on_reply, but for the replies of an unmeasured round such as a prewarm,
+    // which are left out of the target's statistics and outcomes
+    pub fn on_unmeasured_reply(&mut self, reply: &ReceivedPing, at: Instant) -> Option<PingResult> {
+        self.answer(reply, at, false)
+    }
+
+    fn answer(&mut self, reply: &ReceivedPing, at: Instant, measured: bool) -> Option<PingResult> {
+        let mode = self.match_mode;
+        let addr = match mode {
+            MatchMode::Header => reply.addr,
+            MatchMode::PayloadToken => *self.tokens.get(&reply.token?)?,
+        };
+        let ping = self.targets.get_mut(&addr)?;
+        let matches = |seq: u16, token: Option<u64>| match mode {
+            MatchMode::Header => seq == reply.sequence_number,
+            MatchMode::PayloadToken => token == reply.token,
+        };
+        // the probe answered, the latest or one of the round's probes it retried
+        let probe = if matches(ping.sequence_number, ping.token) {
+            Some((ping.sequence_number, ping.sent_at))
+        } else {
+            ping.retried
+                .iter()
+                .find(|&&(seq, token, _)| matches(seq, token))
+                .map(|&(seq, _, sent_at)| (seq, Some(sent_at)))
+        };
+        let (seq, sent_at) = match probe {
+            Some(probe)
+                if mode == MatchMode::PayloadToken || ping.identifier == reply.identifier =>
+            {
+                probe
+            }
+            _ => {
+                debug!("{}Received echo reply from target {}, but sequence_number (expected {} but got {}) and identifier (expected {} but got {}) don't match", self.name, reply.addr, ping.sequence_number, reply.sequence_number, ping.identifier, reply.identifier);
+                return None;
+            }
+        };
+        if ping.seen {
+            debug!(
+                "{}Received duplicate echo reply from target {} for sequence_number {}",
+                self.name, reply.addr, reply.sequence_number
+            );
+            return None;
+        }
+        // a reply can't belong to a probe which was never sent, or whose round is over
+        let sent_at = sent_at?;
+        ping.seen = true;
+        ping.last_seen = at;
+        let rtt = at.saturating_duration_since(sent_at);
+        if measured {
+            ping.stats.on_reply(rtt);
+            ping.outcomes.record(sent_at, true);
+        }
+        Some(PingResult::Receive {
+            addr,
+            rtt,
+            seq,
+            ttl: reply.ttl,
+            identifier: ping.identifier,
+            payload_len: reply.payload_len,
+            // stamped when emitted
+            emitted_at: at,
+            timestamp: None,
+        })
+    }
+
+    // when the round's probes stop waiting for replies, max_rtt after the latest
+    // probe.  None if no round is in progress, or if max_rtt is too long for the
+    // round to ever end
+    pub fn deadline(&self) -> Option<Instant> {
+        self.last_send?.checked_add(self.max_rtt)
+    }
+
+    // end the round once its deadline has passed.  Returns the round's results,
+    // nothing if the round isn't over yet
+    pub fn tick(&mut self, now: Instant) -> Vec<PingResult> {
+        match self.deadline() {
+            Some(deadline) if now >= deadline => self.end_round(now),
+            _ => Vec::new(),
+        }
+    }
+
+    // end the round now, whatever its deadline.  Targets which haven't replied to
+    // their probe are reported Idle, then Pruned and removed if they've been idle
+    // too long.  Targets which weren't probed, such as ones added during the round,
+    // aren't reported.  Replies to the round's probes which arrive later are ignored
+    pub fn end_round(&mut self, now: Instant) -> Vec<PingResult> {
+        self.last_send = None;
+        self.tokens.clear();
+        let mut results = Vec::new();
+        let mut pruned = Vec::new();
+        for (&addr, ping) in self.targets.iter_mut() {
+            let sent_at = ping.sent_at.take();
+            ping.token = None;
+            ping.retried.clear();
+            // a target added during the round had no probe to lose, and isn't idle
+            let sent_at = match sent_at {
+                Some(sent_at) if !ping.seen => sent_at,
+                _ => continue,
+            };
+            ping.stats.on_loss();
+            ping.outcomes.record(sent_at, false);
+            results.push(PingResult::Idle {
+                addr,
+                emitted_at: now,
+                timestamp: None,
+            });
+            if let Some(prune_after) = self.prune_after {
+                if now.saturating_duration_since(ping.last_seen) > prune_after {
+                    debug!("{}Pruning idle target {}", self.name, addr);
+                    pruned.push(addr);
+                    results.push(PingResult::Pruned {
+                        addr,
+                        emitted_at: now,
+                        timestamp: None,
+                    });
+                }
+            }
+        }
+        for addr in pruned {
+            self.remove_target(addr);
+        }
+        results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> IpAddr {
+        s.parse::<IpAddr>().unwrap()
+    }
+
+    fn reply(target: &str, identifier: u16, seq: u16) -> ReceivedPing {
+        ReceivedPing {
+            addr: addr(target),
+            identifier,
+            sequence_number: seq,
+            received_at: Instant::now(),
+            ttl: 64,
+            payload_len: 8,
+            token: None,
+        }
+    }
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    // a core tracking 192.0.2.1 with identifier 7 and 2001:db8::1
+    fn tracked(t0: Instant) -> PingerCore {
+        let mut core = PingerCore::new(ms(100));
+        core.add_target(addr("192.0.2.1"), 7, 0, t0);
+        core.add_target(addr("2001:db8::1"), 0, 0, t0);
+        core
+    }
+
+    #[test]
+    fn test_targets() {
+        let t0 = Instant::now();
+        let mut core = tracked(t0);
+        assert_eq!(core.len(), 2);
+        assert!(!core.is_empty());
+        assert_eq!(core.targets(), vec![addr("192.0.2.1"), addr("2001:db8::1")]);
+        assert_eq!(core.identifier(addr("192.0.2.1")), Some(7));
+        assert_eq!(core.sequence_number(addr("192.0.2.1")), Some(0));
+        assert!(core.identifiers().read().unwrap().contains_key(&7));
+        // ipv6 identifiers aren't tracked
+        assert_eq!(core.identifiers().read().unwrap().len(), 1);
+
+        // replacing a target swaps its identifier
+        core.add_target(addr("192.0.2.1"), 8, 5, t0);
+        assert_eq!(core.len(), 2);
+        assert_eq!(core.sequence_number(addr("192.0.2.1")), Some(5));
+        assert!(!core.identifiers().read().unwrap().contains_key(&7));
+        assert!(core.identifiers().read().unwrap().contains_key(&8));
+
+        assert!(core.remove_target(addr("192.0.2.1")));
+        assert!(!core.remove_target(addr("192.0.2.1")));
+        assert!(!core.contains(addr("192.0.2.1")));
+        assert!(core.identifiers().read().unwrap().is_empty());
+        assert!(!core.on_send(addr("192.0.2.1"), 1, t0));
+    }
+
+    #[test]
+    fn test_on_reply() {
+        let t0 = Instant::now();
+        let mut core = tracked(t0);
+        // nothing is matched before a probe is sent
+        assert!(core.on_reply(&reply("192.0.2.1", 7, 0), t0).is_none());
+
+        assert!(core.on_send(addr("192.0.2.1"), 1, t0));
+        match core.on_reply(&reply("192.0.2.1", 7, 1), t0 + ms(12)) {
+            Some(PingResult::Receive {
+                addr: target,
+                rtt,
+                seq,
+                ttl,
+                identifier,
+                payload_len,
+                ..
+            }) => {
+                assert_eq!(target, addr("192.0.2.1"));
+                assert_eq!(rtt, ms(12));
+                assert_eq!(seq, 1);
+                assert_eq!(ttl, 64);
+                assert_eq!(identifier, 7);
+                assert_eq!(payload_len, 8);
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        // each probe is reported once
+        assert!(core
+            .on_reply(&reply("192.0.2.1", 7, 1), t0 + ms(13))
+            .is_none());
+    }
+
+    #[test]
+    fn test_stats() {
+        let t0 = Instant::now();
+        let mut core = tracked(t0);
+        let v4 = addr("192.0.2.1");
+        assert_eq!(core.stats(v4), Some(TargetStats::default()));
+
+        core.on_send(v4, 1, t0);
+        core.on_reply(&reply("192.0.2.1", 7, 1), t0 + ms(20));
+        core.tick(t0 + ms(100));
+        core.on_send(v4, 2, t0 + ms(100));
+        core.tick(t0 + ms(200));
+        let stats = core.stats(v4).unwrap();
+        assert_eq!(stats.replies, 1);
+        assert_eq!(stats.lost, 1);
+        assert_eq!(stats.srtt, Some(ms(20)));
+        // 2001:db8::1 was never probed, so it lost nothing
+        assert_eq!(core.stats(addr("2001:db8::1")).unwrap().lost, 0);
+        assert_eq!(core.stats(addr("192.0.2.9")), None);
+    }
+
+    #[test]
+    fn test_last_send_ok() {
+        let t0 = Instant::now();
+        let mut core = tracked(t0);
+        let v4 = addr("192.0.2.1");
+        assert_eq!(core.last_send_ok(v4), None);
+        core.on_send(v4, 1, t0);
+        core.on_send_result(v4, false);
+        assert_eq!(core.last_send_ok(v4), Some(false));
+        core.on_send(v4, 2, t0 + ms(100));
+        core.on_send_result(v4, true);
+        assert_eq!(core.last_send_ok(v4), Some(true));
+        core.on_send_result(addr("192.0.2.9"), true);
+        assert_eq!(core.last_send_ok(addr("192.0.2.9")), None);
+    }
+
+    #[test]
+    fn test_retries() {
+        let t0 = Instant::now();
+        let mut core = tracked(t0);
+        let v4 = addr("192.0.2.1");
+        let v6 = addr("2001:db8::1");
+        core.on_send(v4, 1, t0);
+        core.on_send(v6, 0, t0);
+        // retries are off by default
+        assert_eq!(core.next_retry(), None);
+
+        // 2 retries split the 100ms max_rtt in 3
+        core.set_retries_per_round(2);
+        assert_eq!(core.next_retry(), Some(t0 + ms(100) / 3));
+        assert!(core.retries_due(t0 + ms(20)).is_empty());
+        // the ipv6 target's retry couldn't be told from its probe
+        assert_eq!(core.retries_due(t0 + ms(40)), vec![v4]);
+        assert!(core.on_retry(v4, 2, None, t0 + ms(40)));
+        assert_eq!(core.next_retry(), Some(t0 + ms(40) + ms(100) / 3));
+
+        // a late reply to the first probe still counts, and only once
+        assert_eq!(
+            core.on_reply(&reply("192.0.2.1", 7, 1), t0 + ms(50)),
+            Some(PingResult::Receive {
+                addr: v4,
+                rtt: ms(50),
+                seq: 1,
+                ttl: 64,
+                identifier: 7,
+                payload_len: 8,
+                emitted_at: t0 + ms(50),
+                timestamp: None,
+            })
+        );
+        assert_eq!(core.on_reply(&reply("192.0.2.1", 7, 2), t0 + ms(55)), None);
+        assert!(!core.on_retry(v4, 3, None, t0 + ms(80)));
+        assert_eq!(core.next_retry(), None);
+        // the retries didn't stretch the round
+        assert_eq!(core.deadline(), Some(t0 + ms(100)));
+        assert_eq!(
+            core.tick(t0 + ms(100)),
+            vec![PingResult::Idle {
+                addr: v6,
+                emitted_at: t0 + ms(100),
+                timestamp: None,
+            }]
+        );
+        assert_eq!(core.on_reply(&reply("192.0.2.1", 7, 2), t0 + ms(110)), None);
+        assert!(!core.on_retry(v4, 3, None, t0 + ms(110)));
+    }
+
+    #[test]
+    fn test_unmeasured_reply() {
+        let t0 = Instant::now();
+        let mut core = tracked(t0);
+        let v4 = addr("192.0.2.1");
+        core.on_send(v4, 1, t0);
+        assert!(core
+            .on_unmeasured_reply(&reply("192.0.2.1", 7, 1), t0 + ms(20))
+            .is_some());
+        assert_eq!(core.stats(v4), Some(TargetStats::default()));
+        assert_eq!(core.windowed_loss(v4, ms(1000), t0 + ms(100)), None);
+    }
+
+    #[test]
+    fn test_endless_max_rtt() {
+        // a max_rtt past the end of time waits for replies for good
+        let t0 = Instant::now();
+        let mut core = tracked(t0);
+        core.set_max_rtt(Duration::MAX);
+        core.set_retries_per_round(1);
+        core.on_send(addr("192.0.2.1"), 1, t0);
+        assert_eq!(core.deadline(), None);
+        assert!(core.tick(t0 + ms(1000)).is_empty());
+        assert_eq!(core.next_retry(), None);
+        assert!(core.retries_due(t0 + ms(1000)).is_empty());
+        assert!(core
+            .on_reply(&reply("192.0.2.1", 7, 1), t0 + ms(2000))
+            .is_some());
+    }
+
+    #[test]
+    fn test_windowed_loss() {
+        let t0 = Instant::now();
+        let mut core = tracked(t0);
+        let v4 = addr("192.0.2.1");
+        assert_eq!(core.windowed_loss(v4, ms(1000), t0), None);
+
+        // one round every 100ms, the 2nd and 3rd probes go unanswered
+        for (round, &answered) in [true, false, false, true, true].iter().enumerate() {
+            let sent_at = t0 + ms(100 * round as u64);
+            let seq = round as u16 + 1;
+            core.on_send(v4, seq, sent_at);
+            if answered {
+                core.on_reply(&reply("192.0.2.1", 7, seq), sent_at + ms(10));
+            }
+            core.tick(sent_at + ms(100));
+        }
+        let now = t0 + ms(500);
+        assert_eq!(core.windowed_loss(v4, ms(250), now), Some(0.0));
+        assert_eq!(core.windowed_loss(v4, ms(450), now), Some(0.5));
+        // a window reaching back before the target was added covers its lifetime
+        assert_eq!(
+            core.windowed_loss(v4, Duration::from_secs(60), now),
+            Some(0.4)
+        );
+        // no probe was sent within the last 50ms
+        assert_eq!(core.windowed_loss(v4, ms(50), now), None);
+        assert_eq!(core.windowed_loss(addr("192.0.2.9"), ms(450), now), None);
+    }
+
+    #[test]
+    fn test_on_reply_mismatch() {
+        let t0 = Instant::now();
+        let mut core = tracked(t0);
+        core.on_send(addr("192.0.2.1"), 2, t0);
+        // stale sequence number, foreign identifier, unknown target
+        assert!(core.on_reply(&reply("192.0.2.1", 7, 1), t0).is_none());
+        assert!(core.on_reply(&reply("192.0.2.1", 8, 2), t0).is_none());
+        assert!(core.on_reply(&reply("192.0.2.9", 7, 2), t0).is_none());
+        // the outstanding probe is still open
+        assert!(core.on_reply(&reply("192.0.2.1", 7, 2), t0).is_some());
+    }
+
+    #[test]
+    fn test_payload_token() {
+        let t0 = Instant::now();
+        let mut core = tracked(t0);
+        core.set_match_mode(MatchMode::PayloadToken);
+        core.on_send_with_token(addr("192.0.2.1"), 1, 0xfeed, t0);
+        core.on_send_with_token(addr("2001:db8::1"), 0, 0xbeef, t0);
+
+        // the header is ignored, even the source address
+        let mut rewritten = reply("198.51.100.1", 4242, 99);
+        assert!(core.on_reply(&rewritten, t0).is_none());
+        rewritten.token = Some(0xfeed);
+        match core.on_reply(&rewritten, t0 + ms(3)) {
+            Some(PingResult::Receive {
+                addr: target,
+                seq,
+                identifier,
+                rtt,
+                ..
+            }) => {
+                assert_eq!(target, addr("192.0.2.1"));
+                assert_eq!(seq, 1);
+                assert_eq!(identifier, 7);
+                assert_eq!(rtt, ms(3));
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(core.on_reply(&rewritten, t0 + ms(4)).is_none());
+
+        // a new probe retires the old token, as does removing the target
+        core.on_send_with_token(addr("192.0.2.1"), 2, 0xf00d, t0 + ms(10));
+        assert!(core.on_reply(&rewritten, t0 + ms(11)).is_none());
+        let mut late = reply("2001:db8::1", 0, 0);
+        late.token = Some(0xbeef);
+        assert!(core.remove_target(addr("2001:db8::1")));
+        assert!(core.on_reply(&late, t0 + ms(11)).is_none());
+        assert_eq!(core.tokens.len(), 1);
+
+        // as does the end of the round
+        core.end_round(t0 + ms(200));
+        assert!(core.tokens.is_empty());
+        rewritten.token = Some(0xf00d);
+        assert!(core.on_reply(&rewritten, t0 + ms(210)).is_none());
+
+        // header matching ignores tokens
+        core.set_match_mode(MatchMode::Header);
+        core.on_send_with_token(addr("192.0.2.1"), 3, 0xf00d, t0 + ms(300));
+        assert!(core.on_reply(&rewritten, t0 + ms(301)).is_none());
+        let mut intact = reply("192.0.2.1", 7, 3);
+        intact.token = Some(0xf00d);
+        assert!(core.on_reply(&intact, t0 + ms(301)).is_some());
+    }
+
+    #[test]
+    fn test_rtt_clock_skew() {
+        // a reply stamped before its probe was sent counts as an instant reply
+        let t0 = Instant::now();
+        let mut core = tracked(t0);
+        core.on_send(addr("192.0.2.1"), 1, t0 + ms(5));
+        match core.on_reply(&reply("192.0.2.1", 7, 1), t0) {
+            Some(PingResult::Receive { rtt, .. }) => assert_eq!(rtt, ms(0)),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_tick() {
+        let t0 = Instant::now();
+        let mut core = tracked(t0);
+        assert_eq!(core.deadline(), None);
+        assert!(core.tick(t0 + ms(1000)).is_empty());
+
+        core.on_send(addr("192.0.2.1"), 1, t0);
+        core.on_send(addr("2001:db8::1"), 0, t0 + ms(20));
+        // the deadline follows the latest probe
+        assert_eq!(core.deadline(), Some(t0 + ms(120)));
+        core.on_reply(&reply("2001:db8::1", 0, 0), t0 + ms(30));
+        assert!(core.tick(t0 + ms(119)).is_empty());
+
+        let results = core.tick(t0 + ms(120));
+        assert_eq!(
+            results,
+            vec![PingResult::Idle {
+                addr: addr("192.0.2.1"),
+                emitted_at: t0 + ms(120),
+                timestamp: None,
+            }]
+        );
+        assert_eq!(core.deadline(), None);
+        assert!(core.tick(t0 + ms(500)).is_empty());
+    }
+
+    #[test]
+    fn test_late_reply() {
+        // once the round is over its probes can't be answered any more
+        let t0 = Instant::now();
+        let mut core = tracked(t0);
+        core.on_send(addr("192.0.2.1"), 1, t0);
+        // 2001:db8::1 wasn't probed, so it isn't idle either
+        assert_eq!(core.end_round(t0 + ms(100)).len(), 1);
+        assert!(core
+            .on_reply(&reply("192.0.2.1", 7, 1), t0 + ms(150))
+            .is_none());
+
+        // the next round starts afresh
+        core.on_send(addr("192.0.2.1"), 2, t0 + ms(200));
+        core.on_send(addr("2001:db8::1"), 0, t0 + ms(200));
+        assert!(core
+            .on_reply(&reply("192.0.2.1", 7, 2), t0 + ms(210))
+            .is_some());
+        assert_eq!(
+            core.end_round(t0 + ms(300)),
+            vec![PingResult::Idle {
+                addr: addr("2001:db8::1"),
+                emitted_at: t0 + ms(300),
+                timestamp: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_prune() {
+        let t0 = Instant::now();
+        let mut core = tracked(t0);
+        core.set_prune_after(Some(ms(250)));
+        for round in 0..3 {
+            let sent = t0 + ms(100 * round);
+            core.on_send(addr("192.0.2.1"), round as u16 + 1, sent);
+            core.on_send(addr("2001:db8::1"), 0, sent);
+            core.on_reply(&reply("2001:db8::1", 0, 0), sent + ms(10));
+            let results = core.tick(sent + ms(100));
+            assert_eq!(results.len(), if round < 2 { 1 } else { 2 });
+        }
+        // idle for 300ms by the end of the third round
+        assert_eq!(core.targets(), vec![addr("2001:db8::1")]);
+        assert!(core.identifiers().read().unwrap().is_empty());
+
+        // pruned once, and only after its idle result
+        let mut core = tracked(t0);
+        core.set_prune_after(Some(ms(50)));
+        core.on_send(addr("192.0.2.1"), 1, t0);
+        core.on_send(addr("2001:db8::1"), 0, t0);
+        assert_eq!(
+            core.tick(t0 + ms(100)),
+            vec![
+                PingResult::Idle {
+                    addr: addr("192.0.2.1"),
+                    emitted_at: t0 + ms(100),
+                    timestamp: None,
+                },
+                PingResult::Pruned {
+                    addr: addr("192.0.2.1"),
+                    emitted_at: t0 + ms(100),
+                    timestamp: None,
+                },
+                PingResult::Idle {
+                    addr: addr("2001:db8::1"),
+                    emitted_at: t0 + ms(100),
+                    timestamp: None,
+                },
+                PingResult::Pruned {
+                    addr: addr("2001:db8::1"),
+                    emitted_at: t0 + ms(100),
+                    timestamp: None,
+                },
+            ]
+        );
+        assert!(core.is_empty());
+    }
+
+    #[test]
+    fn test_interleaved_targets() {
+        // replies for many targets arriving out of order are each matched to
+        // their own probe
+        let t0 = Instant::now();
+        let mut core = PingerCore::new(ms(100));
+        for host in 1..=50u16 {
+            let target = addr(&format!("192.0.2.{}", host));
+            core.add_target(target, host, 0, t0);
+            core.on_send(target, host, t0 + ms(u64::from(host)));
+        }
+        let mut received = 0;
+        for host in (1..=50u16).rev() {
+            let target = format!("192.0.2.{}", host);
+            if host % 5 == 0 {
+                continue;
+            }
+            match core.on_reply(&reply(&target, host, host), t0 + ms(60)) {
+                Some(PingResult::Receive { rtt, .. }) => {
+                    assert_eq!(rtt, ms(60 - u64::from(host)));
+                    received += 1;
+                }
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+        assert_eq!(received, 40);
+        let idle = core.tick(t0 + ms(150));
+        assert_eq!(idle.len(), 10);
+        for result in idle {
+            match result {
+                PingResult::Idle { addr, .. } => match addr {
+                    IpAddr::V4(v4) => assert_eq!(v4.octets()[3] % 5, 0),
+                    IpAddr::V6(_) => panic!("unexpected target {}", addr),
+                },
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+    }
+}
@@ -0,0 +1,208 @@
+#[cfg(feature = "ipnet")]
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+use std::collections::HashMap;
+use std::net::IpAddr;
+#[cfg(not(feature = "ipnet"))]
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
+
+// limit on how fast the targets sharing a network prefix are probed.  Targets
+// behind one CPE or rate limiting firewall get their probes spread across the
+// round instead of arriving in a burst that gets them dropped and reported idle
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PrefixCap {
+    // prefix length ipv4 targets are grouped by.  Default is 24
+    pub v4_prefix: u8,
+    // prefix length ipv6 targets are grouped by.  Default is 64
+    pub v6_prefix: u8,
+    // probes sent to each group per second
+    pub per_second: u32,
+}
+
+impl PrefixCap {
+    pub fn new(per_second: u32) -> PrefixCap {
+        PrefixCap {
+            v4_prefix: 24,
+            v6_prefix: 64,
+            per_second,
+        }
+    }
+
+    // the network a target is grouped into.  Prefixes longer than the address are
+    // treated as a full length prefix
+    #[cfg(feature = "ipnet")]
+    pub fn group_net(&self, addr: IpAddr) -> IpNet {
+        match addr {
+            IpAddr::V4(v4) => Ipv4Net::new_assert(v4, self.v4_prefix.min(32))
+                .trunc()
+                .into(),
+            IpAddr::V6(v6) => Ipv6Net::new_assert(v6, self.v6_prefix.min(128))
+                .trunc()
+                .into(),
+        }
+    }
+
+    // the network address of the group a target belongs to
+    #[cfg(feature = "ipnet")]
+    pub fn group(&self, addr: IpAddr) -> IpAddr {
+        self.group_net(addr).network()
+    }
+
+    // the network address of the group a target belongs to
+    #[cfg(not(feature = "ipnet"))]
+    pub fn group(&self, addr: IpAddr) -> IpAddr {
+        match addr {
+            IpAddr::V4(v4) => {
+                let prefix = u32::from(self.v4_prefix.min(32));
+                let mask = if prefix == 0 {
+                    0
+                } else {
+                    !0u32 << (32 - prefix)
+                };
+                IpAddr::V4(Ipv4Addr::from(u32::from(v4) & mask))
+            }
+            IpAddr::V6(v6) => {
+                let prefix = u32::from(self.v6_prefix.min(128));
+                let mask = if prefix == 0 {
+                    0
+                } else {
+                    !0u128 << (128 - prefix)
+                };
+                IpAddr::V6(Ipv6Addr::from(u128::from(v6) & mask))
+            }
+        }
+    }
+
+    // when each target should be probed, relative to the start of the round.  The
+    // n-th target of a group is due n / per_second seconds in, so no group is sent
+    // more than per_second probes in any second.  Targets are ordered by when
+    // they're due, then by their order in addrs
+    pub fn schedule<I: IntoIterator<Item = IpAddr>>(&self, addrs: I) -> Vec<(Duration, IpAddr)> {
+        let spacing = Duration::from_secs(1) / self.per_second.max(1);
+        let mut counts: HashMap<IpAddr, u32> = HashMap::new();
+        let mut schedule: Vec<(Duration, IpAddr)> = addrs
+            .into_iter()
+            .map(|addr| {
+                let count = counts.entry(self.group(addr)).or_insert(0);
+                let due = spacing * *count;
+                *count += 1;
+                (due, addr)
+            })
+            .collect();
+        // stable, so targets due at the same time keep their order
+        schedule.sort_by_key(|&(due, _)| due);
+        schedule
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn addr(s: &str) -> IpAddr {
+        s.parse::<IpAddr>().unwrap()
+    }
+
+    #[test]
+    fn test_group() {
+        let cap = PrefixCap::new(10);
+        assert_eq!(cap.group(addr("10.1.2.3")), addr("10.1.2.0"));
+        assert_eq!(cap.group(addr("2001:db8:1:2:3::4")), addr("2001:db8:1:2::"));
+
+        let cap = PrefixCap {
+            v4_prefix: 0,
+            v6_prefix: 128,
+            per_second: 10,
+        };
+        assert_eq!(cap.group(addr("10.1.2.3")), addr("0.0.0.0"));
+        assert_eq!(cap.group(addr("2001:db8::4")), addr("2001:db8::4"));
+
+        let cap = PrefixCap {
+            v4_prefix: 32,
+            v6_prefix: 0,
+            per_second: 10,
+        };
+        assert_eq!(cap.group(addr("10.1.2.3")), addr("10.1.2.3"));
+        assert_eq!(cap.group(addr("2001:db8::4")), addr("::"));
+
+        // out of range prefixes group by the whole address
+        let cap = PrefixCap {
+            v4_prefix: 40,
+            v6_prefix: 200,
+            per_second: 10,
+        };
+        assert_eq!(cap.group(addr("10.1.2.3")), addr("10.1.2.3"));
+        assert_eq!(cap.group(addr("2001:db8::4")), addr("2001:db8::4"));
+    }
+
+    #[cfg(feature = "ipnet")]
+    #[test]
+    fn test_group_net() {
+        let net = |s: &str| s.parse::<IpNet>().unwrap();
+        let cap = PrefixCap::new(10);
+        assert_eq!(cap.group_net(addr("10.1.2.3")), net("10.1.2.0/24"));
+        assert_eq!(
+            cap.group_net(addr("2001:db8:1:2:3::4")),
+            net("2001:db8:1:2::/64")
+        );
+
+        let cap = PrefixCap {
+            v4_prefix: 31,
+            v6_prefix: 127,
+            per_second: 10,
+        };
+        assert_eq!(cap.group_net(addr("10.0.0.3")), net("10.0.0.2/31"));
+        assert_eq!(cap.group_net(addr("10.0.0.2")), net("10.0.0.2/31"));
+        assert_eq!(cap.group_net(addr("2001:db8::3")), net("2001:db8::2/127"));
+        assert!(cap.group_net(addr("10.0.0.3")).contains(&addr("10.0.0.2")));
+        assert!(!cap.group_net(addr("10.0.0.3")).contains(&addr("10.0.0.4")));
+    }
+
+    #[test]
+    fn test_schedule() {
+        let cap = PrefixCap::new(4);
+        let schedule = cap.schedule(vec![
+            addr("10.0.0.1"),
+            addr("10.0.0.2"),
+            addr("10.0.1.1"),
+            addr("10.0.0.3"),
+            addr("2001:db8::1"),
+            addr("2001:db8::2"),
+        ]);
+        assert_eq!(
+            schedule,
+            vec![
+                (Duration::from_millis(0), addr("10.0.0.1")),
+                (Duration::from_millis(0), addr("10.0.1.1")),
+                (Duration::from_millis(0), addr("2001:db8::1")),
+                (Duration::from_millis(250), addr("10.0.0.2")),
+                (Duration::from_millis(250), addr("2001:db8::2")),
+                (Duration::from_millis(500), addr("10.0.0.3")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_schedule_dense_prefix() {
+        // a full /24 is spread across a second at 256 probes per second, and no
+        // second ever holds more than per_second probes for the prefix
+        let cap = PrefixCap::new(256);
+        let addrs: Vec<IpAddr> = (0..=255u8)
+            .map(|host| IpAddr::V4(Ipv4Addr::new(192, 0, 2, host)))
+            .collect();
+        let schedule = cap.schedule(addrs.clone());
+        assert_eq!(schedule.len(), 256);
+        assert_eq!(schedule[255].0, Duration::from_secs(1) / 256 * 255);
+        for (i, &(due, target)) in schedule.iter().enumerate() {
+            assert_eq!(target, addrs[i]);
+            assert_eq!(due, Duration::from_secs(1) / 256 * i as u32);
+        }
+
+        let cap = PrefixCap::new(100);
+        let schedule = cap.schedule(addrs);
+        for window in schedule.windows(101) {
+            assert!(window[100].0 - window[0].0 >= Duration::from_secs(1));
+        }
+    }
+}
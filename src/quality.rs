@@ -0,0 +1,237 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+// running reply statistics of a target, since it was added.  srtt and jitter are
+// smoothed the way tcp and rtp do it, srtt moving an 1/8th and jitter a 1/16th
+// of the way towards each new sample
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct TargetStats {
+    // probes answered
+    pub replies: u64,
+    // probes whose round ended without an answer
+    pub lost: u64,
+    // smoothed round trip time, None until the first reply
+    pub srtt: Option<Duration>,
+    // smoothed difference between consecutive round trip times
+    pub jitter: Duration,
+    last_rtt: Option<Duration>,
+}
+
+impl TargetStats {
+    pub fn on_reply(&mut self, rtt: Duration) {
+        self.replies += 1;
+        self.srtt = Some(match self.srtt {
+            Some(srtt) => smooth(srtt, rtt, 8.0),
+            None => rtt,
+        });
+        if let Some(last_rtt) = self.last_rtt {
+            self.jitter = smooth(self.jitter, rtt.abs_diff(last_rtt), 16.0);
+        }
+        self.last_rtt = Some(rtt);
+    }
+
+    pub fn on_loss(&mut self) {
+        self.lost += 1;
+    }
+
+    // fraction of the probes left unanswered, None before any probe's round ended
+    pub fn loss(&self) -> Option<f64> {
+        let probes = self.replies + self.lost;
+        if probes == 0 {
+            None
+        } else {
+            Some(self.lost as f64 / probes as f64)
+        }
+    }
+
+    // health of the target between 0 (worst) and 1 (best), see QualityWeights.
+    // None before any probe's round ended, or if a weight is negative or not finite
+    pub fn quality_score(&self, weights: &QualityWeights) -> Option<f64> {
+        let valid = |weight: f64| weight.is_finite() && weight >= 0.0;
+        if !(valid(weights.loss) && valid(weights.rtt) && valid(weights.jitter)) {
+            return None;
+        }
+        let loss = self.loss()?;
+        // a target which never replied is as bad on rtt and jitter as it gets
+        let (rtt, jitter) = match self.srtt {
+            Some(srtt) => (
+                fraction(srtt, weights.rtt_limit),
+                fraction(self.jitter, weights.jitter_limit),
+            ),
+            None => (1.0, 1.0),
+        };
+        let total = weights.loss + weights.rtt + weights.jitter;
+        if total <= 0.0 {
+            return Some(1.0);
+        }
+        let penalty = weights.loss * loss + weights.rtt * rtt + weights.jitter * jitter;
+        Some(1.0 - penalty / total)
+    }
+}
+
+// how many of a target's latest probe outcomes are kept for windowed loss
+pub const MAX_OUTCOMES: usize = 1024;
+
+// when each of a target's latest probes was sent and whether it was answered,
+// oldest first
+#[derive(Debug, Clone, Default)]
+pub struct Outcomes(VecDeque<(Instant, bool)>);
+
+impl Outcomes {
+    pub fn record(&mut self, sent_at: Instant, answered: bool) {
+        if self.0.len() == MAX_OUTCOMES {
+            self.0.pop_front();
+        }
+        self.0.push_back((sent_at, answered));
+    }
+
+    // fraction of the probes sent since the given time left unanswered, None if
+    // no probe sent since then has an outcome yet
+    pub fn loss_since(&self, since: Option<Instant>) -> Option<f64> {
+        let (probes, lost) = self
+            .0
+            .iter()
+            .filter(|&&(sent_at, _)| since.is_none_or(|since| sent_at >= since))
+            .fold((0, 0), |(probes, lost), &(_, answered)| {
+                (probes + 1, lost + usize::from(!answered))
+            });
+        if probes == 0 {
+            None
+        } else {
+            Some(lost as f64 / probes as f64)
+        }
+    }
+}
+
+// how loss, rtt and jitter count towards a quality score.  Each is scaled to 0-1,
+// rtt and jitter as a fraction of their limit which is where they count as
+// fully bad, and the weighted average of the three taken off 1.  The defaults
+// weigh loss 0.5, rtt 0.3 and jitter 0.2, with limits of 300ms rtt and 50ms
+// jitter, roughly where interactive traffic such as voice degrades
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QualityWeights {
+    pub loss: f64,
+    pub rtt: f64,
+    pub jitter: f64,
+    pub rtt_limit: Duration,
+    pub jitter_limit: Duration,
+}
+
+impl Default for QualityWeights {
+    fn default() -> QualityWeights {
+        QualityWeights {
+            loss: 0.5,
+            rtt: 0.3,
+            jitter: 0.2,
+            rtt_limit: Duration::from_millis(300),
+            jitter_limit: Duration::from_millis(50),
+        }
+    }
+}
+
+fn smooth(average: Duration, sample: Duration, weight: f64) -> Duration {
+    let average = average.as_secs_f64();
+    Duration::from_secs_f64(average + (sample.as_secs_f64() - average) / weight)
+}
+
+fn fraction(value: Duration, limit: Duration) -> f64 {
+    if limit == Duration::from_secs(0) {
+        return 1.0;
+    }
+    (value.as_secs_f64() / limit.as_secs_f64()).min(1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut stats = TargetStats::default();
+        assert_eq!(stats.loss(), None);
+        stats.on_reply(ms(100));
+        assert_eq!(stats.srtt, Some(ms(100)));
+        assert_eq!(stats.jitter, ms(0));
+        stats.on_reply(ms(180));
+        assert_eq!(stats.srtt, Some(ms(110)));
+        assert_eq!(stats.jitter, ms(5));
+        stats.on_loss();
+        stats.on_loss();
+        assert_eq!(stats.loss(), Some(0.5));
+    }
+
+    #[test]
+    fn test_outcomes() {
+        let t0 = Instant::now();
+        let mut outcomes = Outcomes::default();
+        assert_eq!(outcomes.loss_since(None), None);
+        outcomes.record(t0, false);
+        for n in 1..MAX_OUTCOMES as u64 {
+            outcomes.record(t0 + ms(n), true);
+        }
+        assert_eq!(outcomes.loss_since(None), Some(1.0 / MAX_OUTCOMES as f64));
+        assert_eq!(outcomes.loss_since(Some(t0 + ms(1))), Some(0.0));
+        // the oldest outcome makes room for the newest
+        outcomes.record(t0 + ms(MAX_OUTCOMES as u64), true);
+        assert_eq!(outcomes.loss_since(None), Some(0.0));
+    }
+
+    #[test]
+    fn test_quality_score() {
+        let weights = QualityWeights::default();
+        assert_eq!(TargetStats::default().quality_score(&weights), None);
+
+        // 1 of 4 probes lost, 150ms srtt and 10ms jitter
+        let stats = TargetStats {
+            replies: 3,
+            lost: 1,
+            srtt: Some(ms(150)),
+            jitter: ms(10),
+            last_rtt: None,
+        };
+        let expected = 1.0 - (0.5 * 0.25 + 0.3 * 0.5 + 0.2 * 0.2);
+        assert!(close(stats.quality_score(&weights).unwrap(), expected));
+
+        // rtt and jitter beyond their limits count as fully bad
+        let slow = TargetStats {
+            lost: 0,
+            srtt: Some(ms(900)),
+            jitter: ms(80),
+            ..stats
+        };
+        assert!(close(slow.quality_score(&weights).unwrap(), 0.5));
+
+        // a target which never replied scores 0
+        let gone = TargetStats {
+            replies: 0,
+            lost: 3,
+            ..TargetStats::default()
+        };
+        assert!(close(gone.quality_score(&weights).unwrap(), 0.0));
+
+        // only loss counts
+        let loss_only = QualityWeights {
+            rtt: 0.0,
+            jitter: 0.0,
+            ..weights
+        };
+        assert!(close(stats.quality_score(&loss_only).unwrap(), 0.75));
+
+        // weights which could take the score out of 0-1 don't give one
+        for &weight in &[-0.5, f64::NAN, f64::INFINITY] {
+            let invalid = QualityWeights {
+                rtt: weight,
+                ..weights
+            };
+            assert_eq!(stats.quality_score(&invalid), None);
+        }
+    }
+}
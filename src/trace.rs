@@ -0,0 +1,610 @@
+use lock::MutexExt;
+use ping::{Name, ReceivedPing};
+use pinger_core::{MatchMode, PingerCore};
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead, BufWriter, Write};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use PingResult;
+
+// recorder shared between the pinger and its send loop, None when not recording
+pub type SharedRecorder = Arc<Mutex<Option<Recorder>>>;
+
+// a single trace entry.  Each entry is written as one line made of a tag, the
+// number of nanoseconds since recording started and the entry's fields:
+//
+//   A <t> <addr> <identifier> <seq>          target added
+//   D <t> <addr>                             target removed, or pruned
+//   M <t> <mode>                             match mode, header or token
+//   P <t> <addr> <identifier> <seq> [<token>]
+//                                            probe sent
+//   T <t> <addr> <identifier> <seq> [<token>]
+//                                            retry sent within the round
+//   R <t> <addr> <identifier> <seq> <ttl> <payload_len> [<token>]
+//                                            reply handed to the matcher
+//   E <t>                                    end of round, unseen targets are idle
+//   O <t> <addr> <seq> <rtt> <ttl> <identifier> <payload_len>
+//                                            receive result emitted, rtt in nanoseconds
+//   I <t> <addr>                             idle result emitted
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    Add {
+        addr: IpAddr,
+        identifier: u16,
+        seq: u16,
+    },
+    Remove {
+        addr: IpAddr,
+    },
+    Mode(MatchMode),
+    // the token is only set when matching by payload token
+    Probe {
+        addr: IpAddr,
+        identifier: u16,
+        seq: u16,
+        token: Option<u64>,
+    },
+    Retry {
+        addr: IpAddr,
+        identifier: u16,
+        seq: u16,
+        token: Option<u64>,
+    },
+    Reply {
+        addr: IpAddr,
+        identifier: u16,
+        seq: u16,
+        ttl: u8,
+        payload_len: usize,
+        token: Option<u64>,
+    },
+    RoundEnd,
+    Result(PingResult),
+}
+
+impl From<&ReceivedPing> for Event {
+    fn from(reply: &ReceivedPing) -> Event {
+        Event::Reply {
+            addr: reply.addr,
+            identifier: reply.identifier,
+            seq: reply.sequence_number,
+            ttl: reply.ttl,
+            payload_len: reply.payload_len,
+            token: reply.token,
+        }
+    }
+}
+
+pub struct Recorder {
+    name: Name,
+    start: Instant,
+    writer: BufWriter<Box<dyn Write + Send>>,
+}
+
+impl Recorder {
+    pub fn new<W: Write + Send + 'static>(writer: W, name: Name) -> Recorder {
+        Recorder {
+            name,
+            start: Instant::now(),
+            writer: BufWriter::new(Box::new(writer)),
+        }
+    }
+
+    pub fn record(&mut self, at: Instant, event: &Event) {
+        let t = at.saturating_duration_since(self.start).as_nanos();
+        let written = match *event {
+            Event::Add {
+                addr,
+                identifier,
+                seq,
+            } => writeln!(self.writer, "A {} {} {} {}", t, addr, identifier, seq),
+            Event::Remove { addr } => writeln!(self.writer, "D {} {}", t, addr),
+            Event::Mode(mode) => writeln!(
+                self.writer,
+                "M {} {}",
+                t,
+                match mode {
+                    MatchMode::Header => "header",
+                    MatchMode::PayloadToken => "token",
+                }
+            ),
+            Event::Probe {
+                addr,
+                identifier,
+                seq,
+                token,
+            } => writeln!(
+                self.writer,
+                "P {} {} {} {}{}",
+                t,
+                addr,
+                identifier,
+                seq,
+                token_field(token)
+            ),
+            Event::Retry {
+                addr,
+                identifier,
+                seq,
+                token,
+            } => writeln!(
+                self.writer,
+                "T {} {} {} {}{}",
+                t,
+                addr,
+                identifier,
+                seq,
+                token_field(token)
+            ),
+            Event::Reply {
+                addr,
+                identifier,
+                seq,
+                ttl,
+                payload_len,
+                token,
+            } => writeln!(
+                self.writer,
+                "R {} {} {} {} {} {}{}",
+                t,
+                addr,
+                identifier,
+                seq,
+                ttl,
+                payload_len,
+                token_field(token)
+            ),
+            Event::RoundEnd => writeln!(self.writer, "E {}", t),
+            Event::Result(PingResult::Receive {
+                addr,
+                rtt,
+                seq,
+                ttl,
+                identifier,
+                payload_len,
+                ..
+            }) => writeln!(
+                self.writer,
+                "O {} {} {} {} {} {} {}",
+                t,
+                addr,
+                seq,
+                rtt.as_nanos(),
+                ttl,
+                identifier,
+                payload_len
+            ),
+            Event::Result(PingResult::Idle { addr, .. }) => {
+                writeln!(self.writer, "I {} {}", t, addr)
+            }
+            // pruning is recorded as the removal it is, so a replay stops matching
+            // the target from there on
+            Event::Result(PingResult::Pruned { addr, .. }) => {
+                writeln!(self.writer, "D {} {}", t, addr)
+            }
+            // low ttls follow from the receive before them and the pinger's
+            // threshold rather than from the matching, they're left out
+            Event::Result(PingResult::LowTtl { .. }) => Ok(()),
+        };
+        if let Err(e) = written {
+            error!("{}Error writing trace entry: {}", self.name, e);
+        }
+    }
+
+    pub fn flush(&mut self) {
+        if let Err(e) = self.writer.flush() {
+            error!("{}Error flushing trace: {}", self.name, e);
+        }
+    }
+}
+
+// an optional trailing token field, left out of entries without a token
+fn token_field(token: Option<u64>) -> String {
+    match token {
+        Some(token) => format!(" {}", token),
+        None => String::new(),
+    }
+}
+
+// record an event if the pinger is currently tracing
+pub fn record(recorder: &SharedRecorder, at: Instant, event: Event) {
+    if let Some(recorder) = recorder.locked().as_mut() {
+        recorder.record(at, &event);
+    }
+}
+
+#[derive(Debug)]
+pub enum TraceError {
+    // the trace couldn't be read
+    Io(io::Error),
+    // a line of the trace (1-based) couldn't be parsed
+    Parse {
+        line: usize,
+        reason: String,
+    },
+    // the replayed results differ from the recorded ones at the given result index.
+    // None means one side ran out of results before the other
+    Divergence {
+        index: usize,
+        recorded: Box<Option<PingResult>>,
+        replayed: Box<Option<PingResult>>,
+    },
+}
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TraceError::Io(ref e) => write!(f, "error reading trace: {}", e),
+            TraceError::Parse { line, ref reason } => {
+                write!(f, "invalid trace entry on line {}: {}", line, reason)
+            }
+            TraceError::Divergence {
+                index,
+                ref recorded,
+                ref replayed,
+            } => write!(
+                f,
+                "replay diverges at result {}: recorded {:?}, replayed {:?}",
+                index, recorded, replayed
+            ),
+        }
+    }
+}
+
+impl Error for TraceError {}
+
+impl From<io::Error> for TraceError {
+    fn from(e: io::Error) -> TraceError {
+        TraceError::Io(e)
+    }
+}
+
+fn parse_field<T: std::str::FromStr>(
+    fields: &mut std::str::SplitWhitespace,
+    name: &str,
+) -> Result<T, String> {
+    match fields.next() {
+        Some(field) => field
+            .parse::<T>()
+            .map_err(|_| format!("invalid {} {:?}", name, field)),
+        None => Err(format!("missing {}", name)),
+    }
+}
+
+// parse the optional token ending an entry
+fn parse_token(fields: &mut std::str::SplitWhitespace) -> Result<Option<u64>, String> {
+    match fields.next() {
+        Some(field) => field
+            .parse::<u64>()
+            .map(Some)
+            .map_err(|_| format!("invalid token {:?}", field)),
+        None => Ok(None),
+    }
+}
+
+// parse a trace entry, results are stamped with the given emission time
+fn parse_entry(line: &str, emitted_at: Instant) -> Result<(Duration, Event), String> {
+    let mut fields = line.split_whitespace();
+    let tag = fields.next().ok_or_else(|| "empty entry".to_string())?;
+    let at = Duration::from_nanos(parse_field(&mut fields, "timestamp")?);
+    let event = match tag {
+        "A" => Event::Add {
+            addr: parse_field(&mut fields, "address")?,
+            identifier: parse_field(&mut fields, "identifier")?,
+            seq: parse_field(&mut fields, "sequence number")?,
+        },
+        "D" => Event::Remove {
+            addr: parse_field(&mut fields, "address")?,
+        },
+        "M" => Event::Mode(match fields.next() {
+            Some("header") => MatchMode::Header,
+            Some("token") => MatchMode::PayloadToken,
+            Some(mode) => return Err(format!("invalid match mode {:?}", mode)),
+            None => return Err("missing match mode".to_string()),
+        }),
+        "P" => Event::Probe {
+            addr: parse_field(&mut fields, "address")?,
+            identifier: parse_field(&mut fields, "identifier")?,
+            seq: parse_field(&mut fields, "sequence number")?,
+            token: parse_token(&mut fields)?,
+        },
+        "T" => Event::Retry {
+            addr: parse_field(&mut fields, "address")?,
+            identifier: parse_field(&mut fields, "identifier")?,
+            seq: parse_field(&mut fields, "sequence number")?,
+            token: parse_token(&mut fields)?,
+        },
+        "R" => Event::Reply {
+            addr: parse_field(&mut fields, "address")?,
+            identifier: parse_field(&mut fields, "identifier")?,
+            seq: parse_field(&mut fields, "sequence number")?,
+            ttl: parse_field(&mut fields, "ttl")?,
+            payload_len: parse_field(&mut fields, "payload length")?,
+            token: parse_token(&mut fields)?,
+        },
+        "E" => Event::RoundEnd,
+        "O" => Event::Result(PingResult::Receive {
+            addr: parse_field(&mut fields, "address")?,
+            seq: parse_field(&mut fields, "sequence number")?,
+            rtt: Duration::from_nanos(parse_field(&mut fields, "rtt")?),
+            ttl: parse_field(&mut fields, "ttl")?,
+            identifier: parse_field(&mut fields, "identifier")?,
+            payload_len: parse_field(&mut fields, "payload length")?,
+            emitted_at,
+            timestamp: None,
+        }),
+        "I" => Event::Result(PingResult::Idle {
+            addr: parse_field(&mut fields, "address")?,
+            emitted_at,
+            timestamp: None,
+        }),
+        _ => return Err(format!("unknown tag {:?}", tag)),
+    };
+    match fields.next() {
+        Some(extra) => Err(format!("unexpected field {:?}", extra)),
+        None => Ok((at, event)),
+    }
+}
+
+// re-run the matcher against a recorded trace, feeding it the recorded target
+// changes, probes and replies in their original order.  Returns the number of
+// results reproduced, or where the replayed results stop matching the recorded ones
+pub fn replay<R: BufRead>(reader: R) -> Result<usize, TraceError> {
+    let base = Instant::now();
+    // rounds end where the trace says they did, so the core's max_rtt is never used
+    let mut core = PingerCore::new(Duration::from_secs(0));
+    let mut recorded = Vec::new();
+    let mut replayed = Vec::new();
+
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        // emission times can't be reproduced, so every result is stamped with
+        // the same base and compared without them
+        let (at, event) = parse_entry(&line, base).map_err(|reason| TraceError::Parse {
+            line: index + 1,
+            reason,
+        })?;
+        let at = base + at;
+        match event {
+            Event::Add {
+                addr,
+                identifier,
+                seq,
+            } => {
+                core.add_target(addr, identifier, seq, at);
+            }
+            Event::Remove { addr } => {
+                core.remove_target(addr);
+            }
+            Event::Mode(mode) => {
+                core.set_match_mode(mode);
+            }
+            Event::Probe {
+                addr, seq, token, ..
+            } => match token {
+                Some(token) => {
+                    core.on_send_with_token(addr, seq, token, at);
+                }
+                None => {
+                    core.on_send(addr, seq, at);
+                }
+            },
+            Event::Retry {
+                addr, seq, token, ..
+            } => {
+                core.on_retry(addr, seq, token, at);
+            }
+            Event::Reply {
+                addr,
+                identifier,
+                seq,
+                ttl,
+                payload_len,
+                token,
+            } => {
+                let reply = ReceivedPing {
+                    addr,
+                    identifier,
+                    sequence_number: seq,
+                    received_at: at,
+                    ttl,
+                    payload_len,
+                    token,
+                };
+                if let Some(mut result) = core.on_reply(&reply, at) {
+                    result.stamp(base, None);
+                    replayed.push(result);
+                }
+            }
+            Event::RoundEnd => {
+                for mut result in core.end_round(at) {
+                    result.stamp(base, None);
+                    replayed.push(result);
+                }
+            }
+            Event::Result(result) => recorded.push(result),
+        }
+    }
+
+    for index in 0..recorded.len().max(replayed.len()) {
+        if recorded.get(index) != replayed.get(index) {
+            return Err(TraceError::Divergence {
+                index,
+                recorded: Box::new(recorded.get(index).cloned()),
+                replayed: Box::new(replayed.get(index).cloned()),
+            });
+        }
+    }
+    Ok(recorded.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use transport::mock::MockTransport;
+    use Pinger;
+
+    const TRACE: &str = "A 0 127.0.0.1 7 0
+A 0 7.7.7.7 9 0
+P 10 127.0.0.1 7 1
+P 20 7.7.7.7 9 1
+R 1010 127.0.0.1 7 1 64 8
+O 1011 127.0.0.1 1 1000 64 7 8
+E 2000
+I 2001 7.7.7.7
+";
+
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_replay() {
+        assert_eq!(replay(TRACE.as_bytes()).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_replay_divergence() {
+        // the recorded rtt no longer matches the probe and reply timestamps
+        let trace = TRACE.replace(
+            "O 1011 127.0.0.1 1 1000 64 7 8",
+            "O 1011 127.0.0.1 1 999 64 7 8",
+        );
+        match replay(trace.as_bytes()) {
+            Err(TraceError::Divergence {
+                index,
+                recorded,
+                replayed,
+            }) => {
+                assert_eq!(index, 0);
+                match (*recorded, *replayed) {
+                    (
+                        Some(PingResult::Receive { rtt: recorded, .. }),
+                        Some(PingResult::Receive { rtt: replayed, .. }),
+                    ) => {
+                        assert_eq!(recorded, Duration::from_nanos(999));
+                        assert_eq!(replayed, Duration::from_nanos(1000));
+                    }
+                    other => panic!("unexpected divergence {:?}", other),
+                }
+            }
+            other => panic!("unexpected replay outcome {:?}", other),
+        }
+
+        // a reply which no longer matches leaves a recorded result unreproduced
+        let trace = TRACE.replace("R 1010 127.0.0.1 7 1 64 8", "R 1010 127.0.0.1 8 1 64 8");
+        match replay(trace.as_bytes()) {
+            Err(TraceError::Divergence { index, .. }) => assert_eq!(index, 0),
+            other => panic!("unexpected replay outcome {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_replay_retry() {
+        // the reply to the first probe arrives after its retry went out
+        let trace = "\
+A 0 127.0.0.1 7 0
+P 10 127.0.0.1 7 1
+T 500 127.0.0.1 7 2
+R 1010 127.0.0.1 7 1 64 8
+O 1011 127.0.0.1 1 1000 64 7 8
+R 1020 127.0.0.1 7 2 64 8
+E 2000
+";
+        assert_eq!(replay(trace.as_bytes()).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_replay_parse_error() {
+        match replay("A 0 127.0.0.1 7 0\nP 10 nonsense 7 1\n".as_bytes()) {
+            Err(TraceError::Parse { line, .. }) => assert_eq!(line, 2),
+            other => panic!("unexpected replay outcome {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let buf = Arc::new(Mutex::new(Vec::new()));
+        let (test_pinger, test_channel) = Pinger::new(Some(500), None).unwrap();
+        test_pinger.record_trace(SharedBuf(buf.clone()));
+        test_pinger.add_ipaddr("127.0.0.1");
+        test_pinger.add_ipaddr("7.7.7.7");
+        test_pinger.ping_once();
+        test_pinger.stop_trace();
+        assert_eq!(test_channel.try_iter().count(), 2);
+
+        let trace = buf.lock().unwrap().clone();
+        assert_eq!(replay(&trace[..]).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_record_existing_targets() {
+        let mock = MockTransport::echo();
+        let (test_pinger, test_channel) = Pinger::with_transports(
+            Some(50),
+            None,
+            Box::new(mock.clone()),
+            Box::new(mock.clone()),
+        );
+        mock.connect(test_pinger.thread_tx.clone());
+        test_pinger.add_ipaddr("192.0.2.1");
+        test_pinger.add_ipaddr("2001:db8::1");
+        test_pinger.ping_once();
+
+        // the targets added before recording started are in the trace
+        let buf = Arc::new(Mutex::new(Vec::new()));
+        test_pinger.record_trace(SharedBuf(buf.clone()));
+        test_pinger.ping_once();
+        test_pinger.stop_trace();
+        assert_eq!(test_channel.try_iter().count(), 4);
+
+        let trace = buf.lock().unwrap().clone();
+        assert_eq!(replay(&trace[..]).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_record_and_replay_token() {
+        // a middlebox rewrites the sequence number of every reply
+        let mock = MockTransport::new(Box::new(|sent| {
+            let mut reply = sent.reply();
+            reply.sequence_number = reply.sequence_number.wrapping_add(100);
+            Ok(vec![reply])
+        }));
+        let (test_pinger, test_channel) = Pinger::with_transports(
+            Some(50),
+            None,
+            Box::new(mock.clone()),
+            Box::new(mock.clone()),
+        );
+        mock.connect(test_pinger.thread_tx.clone());
+        let buf = Arc::new(Mutex::new(Vec::new()));
+        test_pinger.set_match_mode(MatchMode::PayloadToken).unwrap();
+        test_pinger.record_trace(SharedBuf(buf.clone()));
+        test_pinger.add_ipaddr("192.0.2.1");
+        test_pinger.ping_once();
+        test_pinger.set_match_mode(MatchMode::Header).unwrap();
+        test_pinger.ping_once();
+        test_pinger.stop_trace();
+        let results: Vec<_> = test_channel.try_iter().collect();
+        assert!(matches!(results[0], PingResult::Receive { .. }));
+        assert!(matches!(results[1], PingResult::Idle { .. }));
+
+        // replayed in the modes it was recorded in, matching by token and then
+        // by header
+        let trace = buf.lock().unwrap().clone();
+        assert_eq!(replay(&trace[..]).unwrap(), 2);
+    }
+}
@@ -0,0 +1,181 @@
+use pnet::packet::Packet;
+use pnet::transport::TransportSender;
+use std::io;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+// the sending side of a socket probes are written to
+pub trait Transport: Send {
+    fn send_to(&mut self, packet: &[u8], addr: IpAddr) -> io::Result<usize>;
+
+    // time to live, or hop limit, of the packets sent from here on
+    fn set_ttl(&mut self, ttl: u8) -> io::Result<()>;
+}
+
+pub type SharedTransport = Arc<Mutex<Box<dyn Transport>>>;
+
+// already built icmp packet handed to pnet as is
+struct RawPacket<'a>(&'a [u8]);
+
+impl Packet for RawPacket<'_> {
+    fn packet(&self) -> &[u8] {
+        self.0
+    }
+
+    fn payload(&self) -> &[u8] {
+        self.0
+    }
+}
+
+impl Transport for TransportSender {
+    fn send_to(&mut self, packet: &[u8], addr: IpAddr) -> io::Result<usize> {
+        TransportSender::send_to(self, RawPacket(packet), addr)
+    }
+
+    fn set_ttl(&mut self, ttl: u8) -> io::Result<()> {
+        TransportSender::set_ttl(self, ttl)
+    }
+}
+
+#[cfg(test)]
+pub mod mock {
+    use super::*;
+    use lock::MutexExt;
+    use ping::{payload_token, ReceivedPing};
+    use std::sync::mpsc::Sender;
+    use std::thread;
+    use std::time::Instant;
+
+    // a probe written to the mock transport
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Sent {
+        pub addr: IpAddr,
+        pub packet: Vec<u8>,
+        pub at: Instant,
+        // the ttl last set on the transport, None if never set
+        pub ttl: Option<u8>,
+    }
+
+    impl Sent {
+        pub fn identifier(&self) -> u16 {
+            u16::from_be_bytes([self.packet[4], self.packet[5]])
+        }
+
+        pub fn sequence_number(&self) -> u16 {
+            u16::from_be_bytes([self.packet[6], self.packet[7]])
+        }
+
+        // the echo reply a target would send back for this probe
+        pub fn reply(&self) -> ReceivedPing {
+            ReceivedPing {
+                addr: self.addr,
+                identifier: self.identifier(),
+                sequence_number: self.sequence_number(),
+                received_at: Instant::now(),
+                ttl: 64,
+                payload_len: self.packet.len() - 8,
+                token: payload_token(&self.packet[8..]),
+            }
+        }
+    }
+
+    // decides what happens to each probe: an error fails the send, otherwise the
+    // returned replies are handed to the pinger as if the listener received them.
+    // Replies stamped with a received_at in the future are held back until then
+    pub type Responder = Box<dyn FnMut(&Sent) -> io::Result<Vec<ReceivedPing>> + Send>;
+
+    struct State {
+        sent: Vec<Sent>,
+        responder: Responder,
+        replies: Option<Sender<ReceivedPing>>,
+        ttl: Option<u8>,
+        ttl_fails: bool,
+    }
+
+    // transport which records every probe instead of putting it on the wire.
+    // Clones share the same state so a test can keep a handle to inspect it
+    #[derive(Clone)]
+    pub struct MockTransport {
+        state: Arc<Mutex<State>>,
+    }
+
+    impl MockTransport {
+        pub fn new(responder: Responder) -> MockTransport {
+            MockTransport {
+                state: Arc::new(Mutex::new(State {
+                    sent: Vec::new(),
+                    responder,
+                    replies: None,
+                    ttl: None,
+                    ttl_fails: false,
+                })),
+            }
+        }
+
+        // a mock where every target is unreachable
+        pub fn silent() -> MockTransport {
+            MockTransport::new(Box::new(|_| Ok(Vec::new())))
+        }
+
+        // a mock where every target replies straight away
+        pub fn echo() -> MockTransport {
+            MockTransport::new(Box::new(|sent| Ok(vec![sent.reply()])))
+        }
+
+        pub fn connect(&self, replies: Sender<ReceivedPing>) {
+            self.state.locked().replies = Some(replies);
+        }
+
+        pub fn sent(&self) -> Vec<Sent> {
+            self.state.locked().sent.clone()
+        }
+
+        // the ttl last set, None if never set
+        pub fn ttl(&self) -> Option<u8> {
+            self.state.locked().ttl
+        }
+
+        // make setting the ttl fail from here on, or succeed again
+        pub fn fail_ttl(&self, fails: bool) {
+            self.state.locked().ttl_fails = fails;
+        }
+    }
+
+    impl Transport for MockTransport {
+        fn send_to(&mut self, packet: &[u8], addr: IpAddr) -> io::Result<usize> {
+            let mut state = self.state.locked();
+            let sent = Sent {
+                addr,
+                packet: packet.to_vec(),
+                at: Instant::now(),
+                ttl: state.ttl,
+            };
+            let replies = (state.responder)(&sent)?;
+            state.sent.push(sent);
+            if let Some(ref tx) = state.replies {
+                for reply in replies {
+                    let now = Instant::now();
+                    if reply.received_at > now {
+                        let tx = tx.clone();
+                        thread::spawn(move || {
+                            thread::sleep(reply.received_at - now);
+                            let _ = tx.send(reply);
+                        });
+                    } else {
+                        let _ = tx.send(reply);
+                    }
+                }
+            }
+            Ok(packet.len())
+        }
+
+        fn set_ttl(&mut self, ttl: u8) -> io::Result<()> {
+            let mut state = self.state.locked();
+            if state.ttl_fails {
+                return Err(io::Error::other("ttl rejected"));
+            }
+            state.ttl = Some(ttl);
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use PingResult;
+
+// turns a stream of results into up/down transitions of the targets.  Targets
+// start out up, and a target only changes state after threshold results in a row
+// say so, so a single lost probe or stray reply doesn't flap it
+pub struct Debouncer {
+    threshold: u32,
+    // each target's state and how many results in a row contradicted it
+    targets: HashMap<IpAddr, (bool, u32)>,
+}
+
+impl Debouncer {
+    // a threshold of 0 counts as 1, changing state on the first contradicting result
+    pub fn new(threshold: u32) -> Debouncer {
+        Debouncer {
+            threshold: threshold.max(1),
+            targets: HashMap::new(),
+        }
+    }
+
+    // feed the next result, returning the target and its new state if it changed.
+    // Pruned targets are forgotten and start out up if they come back
+    pub fn observe(&mut self, result: &PingResult) -> Option<(IpAddr, bool)> {
+        let up = match *result {
+            PingResult::Receive { .. } => true,
+            PingResult::Idle { .. } => false,
+            PingResult::Pruned { addr, .. } => {
+                self.targets.remove(&addr);
+                return None;
+            }
+            PingResult::LowTtl { .. } => return None,
+        };
+        let addr = result.addr();
+        let state = self.targets.entry(addr).or_insert((true, 0));
+        if state.0 == up {
+            state.1 = 0;
+            return None;
+        }
+        state.1 += 1;
+        if state.1 < self.threshold {
+            return None;
+        }
+        *state = (up, 0);
+        Some((addr, up))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_debouncer() {
+        let addr = "192.0.2.1".parse::<IpAddr>().unwrap();
+        let idle = PingResult::Idle {
+            addr,
+            emitted_at: Instant::now(),
+            timestamp: None,
+        };
+        let receive = PingResult::Receive {
+            addr,
+            rtt: Duration::from_millis(1),
+            seq: 1,
+            ttl: 64,
+            identifier: 7,
+            payload_len: 8,
+            emitted_at: Instant::now(),
+            timestamp: None,
+        };
+        let mut debouncer = Debouncer::new(3);
+        let observed: Vec<_> = [
+            &receive, &idle, &idle, &receive, &idle, &idle, &idle, &idle, &receive, &receive,
+            &receive,
+        ]
+        .iter()
+        .map(|result| debouncer.observe(result))
+        .collect();
+        assert_eq!(
+            observed,
+            vec![
+                None,
+                None,
+                None,
+                // the reply breaks the run of idles
+                None,
+                None,
+                None,
+                Some((addr, false)),
+                None,
+                None,
+                None,
+                Some((addr, true)),
+            ]
+        );
+
+        // forgotten once pruned, the target starts out up again
+        debouncer.observe(&idle);
+        debouncer.observe(&idle);
+        debouncer.observe(&PingResult::Pruned {
+            addr,
+            emitted_at: Instant::now(),
+            timestamp: None,
+        });
+        assert_eq!(debouncer.observe(&idle), None);
+        assert_eq!(Debouncer::new(0).observe(&idle), Some((addr, false)));
+    }
+}